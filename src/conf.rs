@@ -1,13 +1,22 @@
 use riti::config::Config;
+use windows::core::GUID;
 
-use crate::Result;
+use crate::{
+    Result, global,
+    keymap::{Action, Keymap},
+};
 
 use winreg::RegKey;
 use winreg::enums::*;
 
 /// Reads settings from Windows Registry (QSettings format)
+///
+/// Settings can be overridden per application under `settings\Apps\{exe name}`, by values
+/// at the same path within, e.g. `settings\Apps\wt.exe\settings\FixedLayout` for
+/// `NumberPad` or `settings\Apps\wt.exe\layout` for the layout's `path`.
 pub struct Settings {
     base_key: RegKey,
+    app_key: Option<RegKey>,
 }
 
 impl Settings {
@@ -15,7 +24,9 @@ impl Settings {
     pub fn load_or_create() -> Result<Self> {
         let hkcu = RegKey::predef(HKEY_CURRENT_USER);
         let (base_key, _) = hkcu.create_subkey(r"Software\OpenBangla\Keyboard")?;
-        Ok(Self { base_key })
+        let app_key = global::exe_name()
+            .and_then(|exe| base_key.open_subkey(format!(r"settings\Apps\{exe}")).ok());
+        Ok(Self { base_key, app_key })
     }
 
     // Helper methods for reading values
@@ -24,27 +35,60 @@ impl Settings {
     }
 
     fn get_bool(&self, subkey: &str, name: &str, default: bool) -> bool {
-        self.base_key
-            .open_subkey(subkey)
-            .ok()
-            .and_then(|key| self.get_string_from_key(&key, name))
-            .map(|v| v == "true")
-            .unwrap_or(default)
+        self.get_bool_opt(subkey, name).unwrap_or(default)
+    }
+
+    /// A boolean setting, None if it's not set at all.
+    fn get_bool_opt(&self, subkey: &str, name: &str) -> Option<bool> {
+        self.get_value(subkey, name).map(|v| v == "true")
     }
 
     fn get_string(&self, subkey: &str, name: &str, default: &str) -> String {
-        self.base_key
-            .open_subkey(subkey)
-            .ok()
-            .and_then(|key| self.get_string_from_key(&key, name))
+        self.get_value(subkey, name)
             .unwrap_or_else(|| default.to_string())
     }
 
+    /// The value `name` under `subkey`, as overridden for the application if it is.
+    fn get_value(&self, subkey: &str, name: &str) -> Option<String> {
+        self.app_key
+            .as_ref()
+            .and_then(|key| key.open_subkey(subkey).ok())
+            .and_then(|key| self.get_string_from_key(&key, name))
+            .or_else(|| {
+                self.base_key
+                    .open_subkey(subkey)
+                    .ok()
+                    .and_then(|key| self.get_string_from_key(&key, name))
+            })
+    }
+
     pub fn get_enter_key_closes_prev_win(&self) -> bool {
         // self.get_bool_direct("EnterKeyClosesPrevWin", false)
         self.get_bool(r"settings", "EnterKeyClosesPrevWin", false)
     }
 
+    /// What Enter does while composing. Falls back to `EnterKeyClosesPrevWin` when not set.
+    pub fn get_enter_key_action(&self) -> EnterAction {
+        let default = if self.get_enter_key_closes_prev_win() {
+            EnterAction::Commit
+        } else {
+            EnterAction::CommitNewline
+        };
+        EnterAction::parse(&self.get_string(r"settings", "EnterKeyAction", "")).unwrap_or(default)
+    }
+
+    /// What Shift+Enter does while composing.
+    pub fn get_shift_enter_key_action(&self) -> EnterAction {
+        EnterAction::parse(&self.get_string(r"settings", "ShiftEnterKeyAction", ""))
+            .unwrap_or(EnterAction::CommitRaw)
+    }
+
+    /// What happens to the composition when the focus moves away.
+    pub fn get_focus_change_action(&self) -> FocusAction {
+        FocusAction::parse(&self.get_string(r"settings", "FocusChangeAction", ""))
+            .unwrap_or(FocusAction::Abort)
+    }
+
     pub fn get_ansi_encoding(&self) -> bool {
         // self.get_bool_direct("ANSI", false)
         self.get_bool(r"settings", "ANSI", false)
@@ -60,6 +104,51 @@ impl Settings {
         self.get_string("layout", "path", "avro_phonetic")
     }
 
+    /// The keyboard layout keys are translated with, as a hexadecimal HKL. Takes the place
+    /// of the one picked at install time.
+    pub fn get_keyboard_layout(&self) -> Option<String> {
+        Some(self.get_string(r"settings", "KeyboardLayout", ""))
+            .filter(|hkl| !hkl.trim().is_empty())
+    }
+
+    /// Swap the layout in use with `layout\AlternatePath`, returning false if there's no
+    /// alternate layout. An application with a layout of its own has it swapped, along with
+    /// its own alternate if it has one.
+    pub fn switch_layout(&self) -> Result<bool> {
+        let app_layout = self
+            .app_key
+            .as_ref()
+            .and_then(|key| {
+                key.open_subkey_with_flags("layout", KEY_READ | KEY_WRITE)
+                    .ok()
+            })
+            .filter(|key| self.get_string_from_key(key, "path").is_some());
+        let layout_key = match app_layout {
+            Some(key) => key,
+            None => self.base_key.create_subkey("layout")?.0,
+        };
+        let Some(alternate) = self
+            .get_value("layout", "AlternatePath")
+            .filter(|path| !path.is_empty())
+        else {
+            return Ok(false);
+        };
+        let current = self.get_layout_path();
+        layout_key.set_value("path", &alternate)?;
+        layout_key.set_value("AlternatePath", &current)?;
+        Ok(true)
+    }
+
+    // Per application settings
+    /// Leaves every key to the application. Only meaningful as a per application override.
+    pub fn get_disabled(&self) -> bool {
+        self.get_bool(r"settings", "Disabled", false)
+    }
+
+    pub fn get_start_english(&self) -> bool {
+        self.get_bool(r"settings", "StartEnglish", false)
+    }
+
     // Fixed Layout settings
     pub fn get_show_prev_win_fixed(&self) -> bool {
         self.get_bool(r"settings\FixedLayout", "ShowPrevWin", true)
@@ -81,6 +170,23 @@ impl Settings {
         self.get_bool(r"settings\FixedLayout", "NumberPad", true)
     }
 
+    /// Whether the layout in use is a fixed one rather than phonetic.
+    pub fn is_fixed_layout(&self) -> bool {
+        self.get_layout_path() != "avro_phonetic"
+    }
+
+    /// Map keys by where they are rather than what the layout of Windows types with them.
+    /// Only fixed layouts are, phonetic typing goes by the letters.
+    pub fn get_positional_keys_fixed(&self) -> bool {
+        self.get_bool(r"settings\FixedLayout", "PositionalKeys", false) && self.is_fixed_layout()
+    }
+
+    /// Type the AltGr layer of the layout with the right Alt only, leaving Ctrl+Alt (with the
+    /// left Alt) to the applications' shortcuts.
+    pub fn get_right_alt_only_fixed(&self) -> bool {
+        self.get_bool(r"settings\FixedLayout", "RightAltOnly", false)
+    }
+
     pub fn get_old_reph(&self) -> bool {
         self.get_bool(r"settings\FixedLayout", "OldReph", true)
     }
@@ -98,10 +204,110 @@ impl Settings {
         self.get_bool(r"settings\CandidateWin", "Phonetic", true)
     }
 
+    // Preedit settings
+    /// Paint the background of the converted text the candidates are for.
+    pub fn get_highlight_target(&self) -> bool {
+        self.get_bool(r"settings\Preedit", "HighlightTarget", false)
+    }
+
     // Preview Window settings
     pub fn get_suggestion_include_english(&self) -> bool {
         self.get_bool(r"settings\PreviewWin", "IncludeEnglish", true)
     }
+
+    // Emoji settings
+    pub fn get_emoji_enabled(&self) -> bool {
+        self.get_bool(r"settings\Emoji", "Enabled", false)
+    }
+
+    /// The prefix that turns the whole input into an emoji keyword. Empty disables the prefix.
+    pub fn get_emoji_trigger(&self) -> String {
+        self.get_string(r"settings\Emoji", "Trigger", ":")
+    }
+
+    // Transliteration settings
+    /// Show the transliterated selection in the candidate window to be confirmed first.
+    pub fn get_transliteration_preview(&self) -> bool {
+        self.get_bool(r"settings\Transliteration", "Preview", false)
+    }
+
+    // Input scope settings
+    /// `Some(true)` to type English in fields of the named input scope, `Some(false)` to type
+    /// Bangla and `None` to go with the default.
+    pub fn get_input_scope_english(&self, scope: &str) -> Option<bool> {
+        self.get_bool_opt(r"settings\InputScope", scope)
+    }
+
+    // Key binding settings
+    /// The key bindings, each action bound to the chords listed under its name in
+    /// `settings\Keys`, e.g. `NextCandidate` = `Tab, Down, Ctrl+N`.
+    pub fn get_keymap(&self) -> Keymap {
+        let keys = self.base_key.open_subkey(r"settings\Keys").ok();
+        Keymap::load(|action: Action| {
+            keys.as_ref()
+                .and_then(|key| self.get_string_from_key(key, action.name()))
+        })
+    }
+
+    /// The GUID the key named `name` (e.g. `Ctrl+Shift+U`) is preserved with. It's made up
+    /// the first time and kept in `settings\PreservedKeys`, so a key keeps its GUID however
+    /// the bindings are reordered.
+    pub fn get_preserved_key_guid(&self, name: &str) -> Result<GUID> {
+        let (key, _) = self.base_key.create_subkey(r"settings\PreservedKeys")?;
+        if let Some(guid) = self
+            .get_string_from_key(&key, name)
+            .and_then(|guid| u128::from_str_radix(&guid, 16).ok())
+        {
+            return Ok(GUID::from_u128(guid));
+        }
+        let guid = GUID::new()?;
+        key.set_value(name, &format!("{:032X}", guid.to_u128()))?;
+        Ok(guid)
+    }
+}
+
+/// What Enter (or Shift+Enter) does while composing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnterAction {
+    /// Commit the highlighted candidate followed by a new line.
+    CommitNewline,
+    /// Commit the highlighted candidate only, closing the candidate window.
+    Commit,
+    /// Commit the roman text as it was typed.
+    CommitRaw,
+}
+
+impl EnterAction {
+    fn parse(value: &str) -> Option<EnterAction> {
+        match value {
+            "CommitNewline" => Some(EnterAction::CommitNewline),
+            "Commit" => Some(EnterAction::Commit),
+            "CommitRaw" => Some(EnterAction::CommitRaw),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to the composition when the focus moves away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FocusAction {
+    /// Leave the roman text as it was typed.
+    Abort,
+    /// Commit the highlighted candidate.
+    Commit,
+    /// Take the composition out of the document and bring it back when the focus returns.
+    Suspend,
+}
+
+impl FocusAction {
+    fn parse(value: &str) -> Option<FocusAction> {
+        match value {
+            "Abort" => Some(FocusAction::Abort),
+            "Commit" => Some(FocusAction::Commit),
+            "Suspend" => Some(FocusAction::Suspend),
+            _ => None,
+        }
+    }
 }
 
 pub fn set_riti_config(settings: &Settings) -> Config {
@@ -0,0 +1,297 @@
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use log::warn;
+
+use crate::Result;
+
+//----------------------------------------------------------------------------
+//
+//  Dictionary files. Their format follows these 3 rules:
+//
+//  1. Entries are written as `{spelling} {option 1} {option 2} ... {option n}`
+//  2. Single-character options can be written in their code points (U+XXXX)
+//  3. Comments are lines starting with `#`, or follow a `#` standing alone
+//     (a `#` starting an option is part of it, as in `#️⃣`)
+//
+//  Every data file of the input method (emoji, symbols, user dictionaries)
+//  goes through this parser so they all agree on what a valid file is.
+//
+//----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub spelling: String,
+    pub options: Vec<String>,
+    /// 1-based line of the entry's first appearance.
+    pub line: usize,
+}
+
+/// A problem found in a dictionary file. Lines and columns are 1-based, columns count chars.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub reason: Reason,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    #[error("'{0}' is not a valid code point.")]
+    InvalidCodePoint(String),
+    #[error("Spelling is empty. Entries must not be indented.")]
+    EmptySpelling,
+    #[error("'{0}' has no options.")]
+    MissingOptions(String),
+    #[error("'{spelling}' is already defined on line {first}. The options are merged.")]
+    DuplicateSpelling { spelling: String, first: usize },
+    #[error("'{0}' is listed more than once.")]
+    DuplicateOption(String),
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{} {}", self.line, self.column, self.reason)
+    }
+}
+
+/// Entries of one or more dictionary files, sorted by spelling.
+#[derive(Debug, Default, Clone)]
+pub struct Dict {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl Dict {
+    /// Parse the text of a dictionary file. Malformed parts are skipped and reported.
+    pub fn parse(text: &str) -> (Dict, Vec<Diagnostic>) {
+        let mut dict = Dict::default();
+        let mut diagnostics = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            dict.parse_line(index + 1, line, &mut diagnostics);
+        }
+        (dict, diagnostics)
+    }
+
+    /// Read and parse a dictionary file, logging every diagnostic along with the path.
+    pub fn load(path: &Path) -> Result<Dict> {
+        let text = fs::read_to_string(path)?;
+        Ok(Dict::parse_logged(&path.to_string_lossy(), &text))
+    }
+
+    /// Parse text that does not come from a file on disk (e.g. a bundled one), logging
+    /// every diagnostic along with `name`.
+    pub fn parse_logged(name: &str, text: &str) -> Dict {
+        let (dict, diagnostics) = Dict::parse(text);
+        for diagnostic in diagnostics {
+            warn!("{name}:{diagnostic}");
+        }
+        dict
+    }
+
+    fn parse_line(&mut self, line_no: usize, line: &str, diagnostics: &mut Vec<Diagnostic>) {
+        let mut tokens = tokenize(line);
+        let Some((column, spelling)) = tokens.next() else {
+            return;
+        };
+        if column != 1 {
+            diagnostics.push(Diagnostic {
+                line: line_no,
+                column: 1,
+                reason: Reason::EmptySpelling,
+            });
+            return;
+        }
+        let mut options = Vec::new();
+        for (column, token) in tokens {
+            let option = match token.strip_prefix("U+") {
+                Some(hex) => match u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                    Some(ch) => ch.to_string(),
+                    None => {
+                        diagnostics.push(Diagnostic {
+                            line: line_no,
+                            column,
+                            reason: Reason::InvalidCodePoint(token.to_owned()),
+                        });
+                        continue;
+                    }
+                },
+                None => token.to_owned(),
+            };
+            options.push((column, option));
+        }
+        if options.is_empty() {
+            diagnostics.push(Diagnostic {
+                line: line_no,
+                column,
+                reason: Reason::MissingOptions(spelling.to_owned()),
+            });
+            return;
+        }
+
+        if let Some(first) = self.entries.get(spelling).map(|entry| entry.line) {
+            diagnostics.push(Diagnostic {
+                line: line_no,
+                column,
+                reason: Reason::DuplicateSpelling {
+                    spelling: spelling.to_owned(),
+                    first,
+                },
+            });
+        }
+        let entry = self
+            .entries
+            .entry(spelling.to_owned())
+            .or_insert_with(|| Entry {
+                spelling: spelling.to_owned(),
+                options: Vec::new(),
+                line: line_no,
+            });
+        for (column, option) in options {
+            if entry.options.contains(&option) {
+                diagnostics.push(Diagnostic {
+                    line: line_no,
+                    column,
+                    reason: Reason::DuplicateOption(option),
+                });
+            } else {
+                entry.options.push(option);
+            }
+        }
+    }
+
+    /// Lay `other` over `self`. Spellings defined in `other` replace those in `self`.
+    pub fn merge(&mut self, other: Dict) {
+        self.entries.extend(other.entries);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+}
+
+/// Split a line into whitespace separated tokens paired with their 1-based columns,
+/// stopping where a comment starts.
+fn tokenize(line: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut tokens = Vec::new();
+    if line.starts_with('#') {
+        return tokens.into_iter();
+    }
+    let mut start = None;
+    for (column, (offset, ch)) in line.char_indices().enumerate() {
+        match (ch.is_whitespace(), start) {
+            (false, None) => start = Some((column + 1, offset)),
+            (true, Some((token_column, token_offset))) => {
+                tokens.push((token_column, &line[token_offset..offset]));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some((token_column, token_offset)) = start {
+        tokens.push((token_column, &line[token_offset..]));
+    }
+    if let Some(comment) = tokens.iter().position(|&(_, token)| token == "#") {
+        tokens.truncate(comment);
+    }
+    tokens.into_iter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options<'a>(dict: &'a Dict, spelling: &str) -> Option<&'a [String]> {
+        dict.entries
+            .get(spelling)
+            .map(|entry| entry.options.as_slice())
+    }
+
+    #[test]
+    fn comments() {
+        let (dict, diagnostics) = Dict::parse("# a comment\nike 😔 # sad\n#nanpa 🔢\nla ℹ️ #");
+        assert!(diagnostics.is_empty());
+        assert_eq!(options(&dict, "ike").unwrap(), ["😔"]);
+        assert_eq!(options(&dict, "la").unwrap(), ["ℹ️"]);
+        assert!(options(&dict, "#nanpa").is_none());
+        assert_eq!(dict.entries().count(), 2);
+    }
+
+    #[test]
+    fn hash_starting_option() {
+        let (dict, diagnostics) = Dict::parse("nanpa #️⃣ 🔢");
+        assert!(diagnostics.is_empty());
+        assert_eq!(options(&dict, "nanpa").unwrap(), ["#️⃣", "🔢"]);
+    }
+
+    #[test]
+    fn blank_lines() {
+        let (dict, diagnostics) = Dict::parse("\nike 😔\n\n   \n\tla ℹ️\n");
+        assert_eq!(
+            diagnostics,
+            [Diagnostic {
+                line: 5,
+                column: 1,
+                reason: Reason::EmptySpelling,
+            }]
+        );
+        assert_eq!(options(&dict, "ike").unwrap(), ["😔"]);
+        assert_eq!(dict.entries().count(), 1);
+    }
+
+    #[test]
+    fn duplicates_merged() {
+        let (dict, diagnostics) = Dict::parse("ike 😔\nla ℹ️\nike 👎 😔");
+        assert_eq!(
+            diagnostics,
+            [
+                Diagnostic {
+                    line: 3,
+                    column: 1,
+                    reason: Reason::DuplicateSpelling {
+                        spelling: "ike".to_owned(),
+                        first: 1,
+                    },
+                },
+                Diagnostic {
+                    line: 3,
+                    column: 7,
+                    reason: Reason::DuplicateOption("😔".to_owned()),
+                },
+            ]
+        );
+        let ike = dict.entries.get("ike").unwrap();
+        assert_eq!(ike.options, ["😔", "👎"]);
+        assert_eq!(ike.line, 1);
+    }
+
+    #[test]
+    fn code_points() {
+        let (dict, diagnostics) = Dict::parse("taka U+09F3 U+D800 U+XYZ");
+        assert_eq!(options(&dict, "taka").unwrap(), ["৳"]);
+        let columns: Vec<_> = diagnostics.iter().map(|d| d.column).collect();
+        assert_eq!(columns, [13, 20]);
+        assert_eq!(
+            diagnostics[0].reason,
+            Reason::InvalidCodePoint("U+D800".to_owned())
+        );
+    }
+
+    #[test]
+    fn missing_options() {
+        let (dict, diagnostics) = Dict::parse("taka\ntaka # none");
+        assert_eq!(dict.entries().count(), 0);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0].reason,
+            Reason::MissingOptions("taka".to_owned())
+        );
+    }
+
+    #[test]
+    fn merge_precedence() {
+        let (mut dict, _) = Dict::parse("ike 😔\nla ℹ️");
+        let (custom, _) = Dict::parse("ike 👎");
+        dict.merge(custom);
+        assert_eq!(options(&dict, "ike").unwrap(), ["👎"]);
+        assert_eq!(options(&dict, "la").unwrap(), ["ℹ️"]);
+    }
+}
@@ -0,0 +1,164 @@
+use std::{collections::BTreeMap, env, path::PathBuf, sync::OnceLock};
+
+use log::debug;
+
+use crate::{Result, dict::Dict, global::IME_NAME};
+
+//----------------------------------------------------------------------------
+//
+//  The emoji and symbol layer. Keywords typed by the user are looked up in
+//  tables written in the same format as the dictionary files. Entries of the
+//  bundled tables can be overridden by putting files of the same name under
+//  %APPDATA%/OpenBangla/dict.
+//
+//----------------------------------------------------------------------------
+
+const BUNDLED: [(&str, &str); 2] = [
+    ("emoji.dict", include_str!("../res/dict/emoji.dict")),
+    ("symbol.dict", include_str!("../res/dict/symbol.dict")),
+];
+
+/// Keyword to emoji (or symbol) mapping, sorted by keyword so that prefixes can be looked up.
+#[derive(Default)]
+pub struct EmojiTable {
+    entries: BTreeMap<String, Vec<String>>,
+}
+
+impl EmojiTable {
+    /// The table shared by every text service in the process. Loaded once on first use.
+    pub fn get() -> &'static EmojiTable {
+        static INSTANCE: OnceLock<EmojiTable> = OnceLock::new();
+        INSTANCE.get_or_init(EmojiTable::load)
+    }
+
+    fn load() -> EmojiTable {
+        let dir = dict_dir();
+        let dicts = BUNDLED.map(|(file_name, bundled)| {
+            let mut dict = Dict::parse_logged(file_name, bundled);
+            // custom files take precedence over the bundled ones, spelling by spelling
+            if let Ok(dir) = dir.as_ref()
+                && let Ok(custom) = Dict::load(&dir.join(file_name))
+            {
+                debug!("Loaded custom {file_name}.");
+                dict.merge(custom);
+            }
+            dict
+        });
+        let table = EmojiTable::from_dicts(dicts);
+        debug!("Loaded {} emoji keywords.", table.entries.len());
+        table
+    }
+
+    /// Keywords are looked up in lower case. A keyword found in several tables lists the
+    /// options of each, every option once.
+    fn from_dicts(dicts: impl IntoIterator<Item = Dict>) -> EmojiTable {
+        let mut table = EmojiTable::default();
+        for dict in dicts {
+            for entry in dict.entries() {
+                let options = table
+                    .entries
+                    .entry(entry.spelling.to_lowercase())
+                    .or_default();
+                for option in &entry.options {
+                    if !options.contains(option) {
+                        options.push(option.clone());
+                    }
+                }
+            }
+        }
+        table
+    }
+
+    /// Emoji listed under `keyword` exactly.
+    pub fn exact(&self, keyword: &str) -> &[String] {
+        self.entries
+            .get(&keyword.to_lowercase())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Emoji whose keyword starts with `prefix`, the shorter keywords first.
+    pub fn search(&self, prefix: &str, limit: usize) -> Vec<String> {
+        let prefix = prefix.to_lowercase();
+        let mut matches: Vec<_> = self
+            .entries
+            .range(prefix.clone()..)
+            .take_while(|(spelling, _)| spelling.starts_with(&prefix))
+            .collect();
+        matches.sort_by_key(|(spelling, _)| spelling.len());
+        let mut found: Vec<String> = Vec::with_capacity(limit);
+        for option in matches.into_iter().flat_map(|(_, options)| options) {
+            if found.len() == limit {
+                break;
+            }
+            if !found.contains(option) {
+                found.push(option.clone());
+            }
+        }
+        found
+    }
+}
+
+fn dict_dir() -> Result<PathBuf> {
+    Ok(PathBuf::from(env::var("APPDATA")?)
+        .join(IME_NAME)
+        .join("dict"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(texts: &[&str]) -> EmojiTable {
+        EmojiTable::from_dicts(texts.iter().map(|text| Dict::parse(text).0))
+    }
+
+    #[test]
+    fn exact() {
+        let table = table(&["heart ❤️ 💕\nheartbeat 💓"]);
+        assert_eq!(table.exact("heart"), ["❤️", "💕"]);
+        assert!(table.exact("hear").is_empty());
+        assert!(table.exact("").is_empty());
+    }
+
+    #[test]
+    fn case_folding() {
+        let table = table(&["Smile 😊\nsmile 🙂"]);
+        assert_eq!(table.exact("smile"), ["😊", "🙂"]);
+        assert_eq!(table.exact("SMILE"), ["😊", "🙂"]);
+        assert_eq!(table.search("SMI", 5), ["😊", "🙂"]);
+    }
+
+    #[test]
+    fn prefix_order() {
+        let table = table(&["heartbeat 💓\nhearts 💕\nheart ❤️\nhe 👨\nhello 👋"]);
+        // the shorter keywords first, then in keyword order
+        assert_eq!(table.search("hea", 10), ["❤️", "💕", "💓"]);
+        assert_eq!(table.search("he", 10), ["👨", "❤️", "👋", "💕", "💓"]);
+        assert!(table.search("x", 10).is_empty());
+    }
+
+    #[test]
+    fn limit() {
+        let table = table(&["cat 🐱 🐈\ncats 😺 😸"]);
+        assert_eq!(table.search("cat", 3), ["🐱", "🐈", "😺"]);
+        assert_eq!(table.search("cat", 1), ["🐱"]);
+        assert!(table.search("cat", 0).is_empty());
+    }
+
+    #[test]
+    fn dedup() {
+        // the same emoji under several keywords and in several tables
+        let table = table(&["happy 😊 😄\nhappiness 😊", "happy 😄 🥳"]);
+        assert_eq!(table.exact("happy"), ["😊", "😄", "🥳"]);
+        assert_eq!(table.search("happ", 10), ["😊", "😄", "🥳"]);
+    }
+
+    #[test]
+    fn bundled() {
+        let table = EmojiTable::from_dicts(BUNDLED.map(|(_, text)| Dict::parse(text).0));
+        assert!(table.exact("smile").contains(&"😊".to_string()));
+        assert!(table.exact("hasi").contains(&"😊".to_string()));
+        assert!(table.exact("nanpa").contains(&"#️⃣".to_string()));
+    }
+}
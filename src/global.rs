@@ -1,4 +1,4 @@
-use std::{ffi::OsString, sync::OnceLock};
+use std::{env, ffi::OsString, fs, io, num::ParseIntError, path::PathBuf, sync::OnceLock};
 
 use log::{debug, error};
 use windows::{
@@ -8,12 +8,12 @@ use windows::{
             LibraryLoader::GetModuleFileNameA,
             SystemServices::{LANG_BANGLA, SUBLANG_BANGLA_BANGLADESH},
         },
-        UI::TextServices::HKL,
+        UI::{Input::KeyboardAndMouse::GetKeyboardLayoutList, TextServices::HKL},
     },
     core::GUID,
 };
 
-use crate::Result;
+use crate::{Error, Result, conf::Settings, extend::ResultExt};
 
 pub fn setup(dll_module: HINSTANCE) {
     DLL_MODULE.get_or_init(|| dll_module);
@@ -49,24 +49,63 @@ pub fn dll_path() -> Result<OsString> {
     Ok(path)
 }
 
-// pub fn hkl_or_us() -> HKL {
-//     //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+/// File name of the host process's executable in lower case, e.g. `winword.exe`.
+pub fn exe_name() -> Option<&'static str> {
+    static INSTANCE: OnceLock<Option<String>> = OnceLock::new();
+    INSTANCE
+        .get_or_init(|| {
+            let exe = env::current_exe().ok()?;
+            let name = exe.file_name()?.to_string_lossy().to_lowercase();
+            debug!("Running in {name}.");
+            Some(name)
+        })
+        .as_deref()
+}
+
+/// The keyboard layout keys are translated with, None if none is picked.
+/// It's picked in the settings (`KeyboardLayout`) or at install time (`install.dat`),
+/// as the hexadecimal HKL of a layout installed on the system. Read again on every call
+/// since the settings can change any time.
+pub fn hkl() -> Option<HKL> {
+    //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+    configured_hkl().log_err().ok().flatten()
+}
+
+pub fn hkl_or_us() -> HKL {
+    hkl().unwrap_or(IME_KEYBOARD_US)
+}
 
-//     static INSTANCE: OnceLock<HKL> = OnceLock::new();
-//     *INSTANCE.get_or_init(|| {
-//         // I need try block
-//         let result: Result<HKL> = (|| {
-//             let hkl = PathBuf::from(env::var("LOCALAPPDATA")?)
-//                 .join(IME_NAME)
-//                 .join("install.dat");
-//             let hkl = fs::read_to_string(hkl)?;
-//             let hkl = u32::from_str_radix(&hkl, 16).map_err(Error::InstallDatCorrupted)?;
-//             let hkl = HKL(hkl as isize);
-//             Ok(hkl)
-//         })();
-//         result.log_err().unwrap_or(HKL(LanguageID::US as isize))
-//     })
-// }
+fn configured_hkl() -> Result<Option<HKL>> {
+    if let Some(hkl) = Settings::load_or_create()?.get_keyboard_layout() {
+        return parse_hkl(&hkl, Error::KeyboardLayoutCorrupted).map(Some);
+    }
+    let path = PathBuf::from(env::var("LOCALAPPDATA")?)
+        .join(IME_NAME)
+        .join("install.dat");
+    match fs::read_to_string(path) {
+        Ok(hkl) if hkl.trim().is_empty() => Err(Error::LayoutMissing),
+        Ok(hkl) => parse_hkl(hkl.trim(), Error::InstallDatCorrupted).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Parse an HKL written down in hexadecimal, `corrupted` telling where it was read from.
+fn parse_hkl(hkl: &str, corrupted: fn(ParseIntError) -> Error) -> Result<HKL> {
+    let hkl = u32::from_str_radix(hkl, 16).map_err(corrupted)?;
+    if hkl & 0xFFFF == 0 {
+        return Err(Error::LangidMissing);
+    }
+    let mut installed = [HKL::default(); 64];
+    let len = unsafe { GetKeyboardLayoutList(Some(&mut installed)) } as usize;
+    // the high bits of an HKL are sign-extended on 64-bit
+    installed[..len]
+        .iter()
+        .find(|installed| installed.0 as u32 == hkl)
+        .copied()
+        .ok_or(Error::LayoutInvalid)
+}
 
 // registration stuff
 pub const IME_NAME: &str = "OpenBangla";
@@ -75,6 +114,8 @@ pub const IME_ID: GUID = GUID::from_u128(0x9AC475F8_4229_47F5_A08A_8A68D3AB1318)
 pub const LANG_PROFILE_ID: GUID = GUID::from_u128(0x77598B34_42C3_4EBC_A0F8_7A7769CA44CD);
 pub const LANGBAR_ITEM_ID: GUID = GUID::from_u128(0x997E9F8B_BB33_43DA_9FB2_5271BAD7C556);
 pub const DISPLAY_ATTR_ID: GUID = GUID::from_u128(0xB0ADCBF2_E221_4CF0_AFED_7C3F7C7AD328);
+pub const DISPLAY_ATTR_CONVERTED_ID: GUID = GUID::from_u128(0x6E4C1F0B_92D7_4A35_B8C6_0F3A5D21E947);
+pub const DISPLAY_ATTR_TARGET_ID: GUID = GUID::from_u128(0xC8319A5E_47F2_4D0B_9E6A_B25F0D7C1384);
 pub const TEXTSERVICE_LANGID: u16 = (SUBLANG_BANGLA_BANGLADESH << 10 | LANG_BANGLA) as u16;
 pub const IME_KEYBOARD_US: HKL = HKL(0x00000409);
 pub const ICON_INDEX: u32 = 0;
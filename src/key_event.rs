@@ -0,0 +1,602 @@
+use Input::*;
+use riti::{
+    context::{MODIFIER_ALT_GR, MODIFIER_SHIFT},
+    keycodes::{VC_0, VC_1, VC_2, VC_3, VC_4, VC_5, VC_6, VC_7, VC_8, VC_9},
+};
+
+use crate::{
+    keymap::{Action, Chord, Keymap},
+    tap::Modifier,
+};
+
+//----------------------------------------------------------------------------
+//
+//  Key events as the input method sees them, whatever platform they come
+//  from. A front-end translates its own events into `KeyEvent`s (see
+//  tsf/key_translator.rs for Windows), and from then on the keys are
+//  simplified to `Input`s, and what to do with each is decided from the
+//  state of the text service. The front-end only carries the decision out.
+//  Nothing here needs the platform, so key sequences can be replayed
+//  without it.
+//
+//----------------------------------------------------------------------------
+
+/// What a key is, as far as the input method cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalKey {
+    /// A key typing a character, as the keyboard layout has it.
+    Char(char),
+    Space,
+    Backspace,
+    Tab,
+    Enter,
+    Escape,
+    PageUp,
+    PageDown,
+    End,
+    Home,
+    Left,
+    Up,
+    Right,
+    Down,
+    Delete,
+    /// Shift or Ctrl on its own.
+    Modifier(Modifier),
+    /// Anything else, by its platform's code.
+    Other(u32),
+}
+
+/// The modifiers held down. AltGr is the right Alt, which is an Alt as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+    pub altgr: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: LogicalKey,
+    /// The code of the key on the keyboard, numbered as Windows' virtual-key codes. Key
+    /// bindings go by it, see keymap.rs.
+    pub code: u32,
+    /// The riti keycode of the character, None if riti has no keycode for it.
+    pub keycode: Option<u16>,
+    pub modifiers: Modifiers,
+    /// Whether the key is on the keypad.
+    pub keypad: bool,
+    /// Whether the key was down already, i.e. auto-repeat.
+    pub repeat: bool,
+}
+
+/// Inputs that are easier to understand and handle.
+/// Keys carry riti keycodes, see riti::keycodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Number(usize),
+    Keypad(u16), // a digit on the keypad
+    Key(u16),
+    AltGrKey(u16),
+    ShiftAltGr(u16),
+    Space,
+    Backspace(bool), // is Ctrl
+    Enter(bool),     // is Shift
+    Escape,
+    Tab,
+    Left(bool), // is Ctrl
+    Up,
+    Right(bool), // is Ctrl
+    Down,
+    Home,
+    End,
+    Delete,
+    Bound(Action), // a key bound in the keymap
+    Tap(Modifier), // a modifier pressed and released on its own
+    Unknown(#[allow(dead_code)] LogicalKey),
+}
+
+impl Input {
+    /// Simplify a key event, with the keys bound in `keymap` taken as their actions.
+    pub fn from_event(event: &KeyEvent, keymap: &Keymap) -> Input {
+        let Modifiers {
+            ctrl,
+            shift,
+            alt,
+            altgr,
+        } = event.modifiers;
+
+        // keys bound to actions on the composition take precedence over what they'd do
+        let chord = Chord {
+            key: event.code,
+            ctrl,
+            shift,
+            alt,
+        };
+        if let Some(action) = keymap.action(chord)
+            && !action.is_global()
+        {
+            return Bound(action);
+        }
+
+        match (event.key, event.keycode) {
+            (LogicalKey::Backspace, _) => Backspace(ctrl),
+            (LogicalKey::Tab, _) => Tab,
+            (LogicalKey::Enter, _) => Enter(shift),
+            (LogicalKey::Escape, _) => Escape,
+            (LogicalKey::Space, _) => Space,
+            (LogicalKey::End, _) => End,
+            (LogicalKey::Home, _) => Home,
+            (LogicalKey::Left, _) => Left(ctrl),
+            (LogicalKey::Up, _) => Up,
+            (LogicalKey::Right, _) => Right(ctrl),
+            (LogicalKey::Down, _) => Down,
+            (LogicalKey::Delete, _) => Delete,
+            // the layer of the layout typed with AltGr
+            (LogicalKey::Char(_), Some(keycode)) if altgr && shift => ShiftAltGr(keycode),
+            (LogicalKey::Char(_), Some(keycode)) if altgr => AltGrKey(keycode),
+            // shortcuts of the application
+            (key @ LogicalKey::Char(_), _) if ctrl => Unknown(key),
+            (LogicalKey::Char('0'..='9'), Some(keycode)) if event.keypad => Keypad(keycode),
+            (LogicalKey::Char(number @ '0'..='9'), _) => Number(number as usize - '0' as usize),
+            (LogicalKey::Char(_), Some(keycode)) => Key(keycode),
+            (key, _) => Unknown(key),
+        }
+    }
+}
+
+/// The riti keycodes of the digits of the main block.
+const DIGITS: [u16; 10] = [VC_0, VC_1, VC_2, VC_3, VC_4, VC_5, VC_6, VC_7, VC_8, VC_9];
+
+/// What the text service is in the middle of, as far as deciding on a key goes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct State {
+    pub composing: bool,
+    /// English is the mode in effect.
+    pub english: bool,
+    /// A transliteration waits to be confirmed.
+    pub previewing: bool,
+    /// Backspace would take the last commit back.
+    pub can_undo: bool,
+    pub has_candidates: bool,
+    /// Whether the caret can be moved within the preedit, see `Delete`, `Home` and `End`.
+    pub preedit_editable: bool,
+    /// Whether keypad digits are typed as Bangla.
+    pub number_pad: bool,
+    /// Whether the candidate list is laid out vertically, leaving Left and Right to the caret.
+    pub vertical_candidates: bool,
+}
+
+/// What to do with a key. Anything but `Pass` is tested as eaten.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Leave the key to the application.
+    Pass,
+    /// Eat the key and do nothing.
+    Ignore,
+    /// An action that works whether composing or not.
+    Hotkey(Action),
+    /// An action on the composition.
+    Action(Action),
+    ConfirmPreview,
+    CancelPreview,
+    UndoCommit,
+    /// Type a riti keycode with riti modifiers, starting a composition if there's none.
+    Type(u16, u8),
+    Select(usize),
+    Commit(Option<char>),
+    /// Commit the composition and leave the key to the application after all.
+    CommitAndPass,
+    Enter(bool), // is Shift
+    Pop(bool),   // is Ctrl
+    Delete,
+    SetCaret(usize),
+    MoveCaret(isize),
+}
+
+/// Decide what to do with `input` in `state`. Taps go by their binding in `keymap`.
+pub fn decide(input: Input, state: &State, keymap: &Keymap) -> Decision {
+    // taps do nothing unless bound, and what's not a hotkey needs a composition
+    if let Tap(modifier) = input {
+        let tap = Chord {
+            key: modifier.vkey(),
+            ctrl: false,
+            shift: false,
+            alt: false,
+        };
+        return match keymap.action(tap) {
+            Some(action) if action.is_global() => Decision::Hotkey(action),
+            Some(action) if state.composing => Decision::Action(action),
+            _ => Decision::Pass,
+        };
+    }
+    // anything but these drops the preview and goes on as usual
+    if state.previewing {
+        match input {
+            Space | Enter(_) | Number(1) | Bound(Action::Commit | Action::CommitRaw) => {
+                return Decision::ConfirmPreview;
+            }
+            Escape | Bound(Action::Cancel) => return Decision::CancelPreview,
+            _ => (),
+        }
+    }
+    // Backspace right after a commit takes it back
+    if input == Backspace(false) && state.can_undo {
+        return Decision::UndoCommit;
+    }
+    if !state.composing {
+        if state.english {
+            return Decision::Pass;
+        }
+        // letters start compositions. punctuators need to be re-mapped.
+        return match input {
+            Key(key) => Decision::Type(key, 0),
+            AltGrKey(key) => Decision::Type(key, MODIFIER_ALT_GR),
+            ShiftAltGr(key) => Decision::Type(key, MODIFIER_SHIFT ^ MODIFIER_ALT_GR),
+            // digits are up to the layout, Bangla numerals most of the time
+            Number(number) => Decision::Type(DIGITS[number], 0),
+            Keypad(key) if state.number_pad => Decision::Type(key, 0),
+            _ => Decision::Pass,
+        };
+    }
+    match input {
+        // digits pick candidates only if there are any to pick
+        Number(0) if state.has_candidates => Decision::Ignore,
+        Number(number) if state.has_candidates => Decision::Select(number - 1),
+        Number(number) => Decision::Type(DIGITS[number], 0),
+        Keypad(key) if state.number_pad => Decision::Type(key, 0),
+        // the digit goes to the application as it is
+        Keypad(_) => Decision::CommitAndPass,
+        Key(key) => Decision::Type(key, 0),
+        AltGrKey(key) => Decision::Type(key, MODIFIER_ALT_GR),
+        ShiftAltGr(key) => Decision::Type(key, MODIFIER_SHIFT ^ MODIFIER_ALT_GR),
+        Space => Decision::Commit(Some(' ')),
+        Enter(shift) => Decision::Enter(shift),
+        Backspace(ctrl) => Decision::Pop(ctrl),
+        // the application has the caret if the preedit can't be edited
+        Delete | Home | End if !state.preedit_editable => Decision::Pass,
+        Delete => Decision::Delete,
+        Home => Decision::SetCaret(0),
+        End => Decision::SetCaret(usize::MAX),
+        // the caret moves within the roman input when Left and Right are not needed by the
+        // candidate list, or when Ctrl is held
+        Left(ctrl) if ctrl || state.vertical_candidates => Decision::MoveCaret(-1),
+        Right(ctrl) if ctrl || state.vertical_candidates => Decision::MoveCaret(1),
+        Left(_) => Decision::Action(Action::PrevCandidate),
+        Right(_) => Decision::Action(Action::NextCandidate),
+        Bound(action) => Decision::Action(action),
+        _ => Decision::Pass,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use riti::keycodes::{VC_A, VC_A_SHIFT, VC_C, VC_J, VC_KP_5, VC_U};
+
+    use super::*;
+
+    fn event(key: LogicalKey, code: u32, keycode: Option<u16>) -> KeyEvent {
+        KeyEvent {
+            key,
+            code,
+            keycode,
+            modifiers: Modifiers::default(),
+            keypad: false,
+            repeat: false,
+        }
+    }
+
+    fn with(event: KeyEvent, modifiers: Modifiers) -> KeyEvent {
+        KeyEvent { modifiers, ..event }
+    }
+
+    const CTRL: Modifiers = Modifiers {
+        ctrl: true,
+        shift: false,
+        alt: false,
+        altgr: false,
+    };
+    const SHIFT: Modifiers = Modifiers {
+        ctrl: false,
+        shift: true,
+        alt: false,
+        altgr: false,
+    };
+    const ALTGR: Modifiers = Modifiers {
+        ctrl: false,
+        shift: false,
+        alt: true,
+        altgr: true,
+    };
+
+    fn input(event: KeyEvent) -> Input {
+        Input::from_event(&event, &Keymap::default())
+    }
+
+    fn keymap(settings: &[(Action, &str)]) -> Keymap {
+        Keymap::load(|action| {
+            settings
+                .iter()
+                .find(|(configured, _)| *configured == action)
+                .map(|(_, chords)| chords.to_string())
+        })
+    }
+
+    const IDLE: State = State {
+        composing: false,
+        english: false,
+        previewing: false,
+        can_undo: false,
+        has_candidates: false,
+        preedit_editable: true,
+        number_pad: false,
+        vertical_candidates: false,
+    };
+    const COMPOSING: State = State {
+        composing: true,
+        has_candidates: true,
+        ..IDLE
+    };
+
+    fn decide_default(input: Input, state: State) -> Decision {
+        decide(input, &state, &Keymap::default())
+    }
+
+    #[test]
+    fn characters() {
+        let a = event(LogicalKey::Char('a'), 0x41, Some(VC_A));
+        assert_eq!(input(a), Key(VC_A));
+        let shifted = event(LogicalKey::Char('A'), 0x41, Some(VC_A_SHIFT));
+        assert_eq!(input(with(shifted, SHIFT)), Key(VC_A_SHIFT));
+        // no keycode for it
+        let e = event(LogicalKey::Char('é'), 0x45, None);
+        assert_eq!(input(e), Unknown(LogicalKey::Char('é')));
+    }
+
+    #[test]
+    fn digits() {
+        let five = event(LogicalKey::Char('5'), 0x35, Some(VC_5));
+        assert_eq!(input(five), Number(5));
+        let keypad = KeyEvent {
+            keypad: true,
+            ..event(LogicalKey::Char('5'), 0x65, Some(VC_KP_5))
+        };
+        assert_eq!(input(keypad), Keypad(VC_KP_5));
+    }
+
+    #[test]
+    fn altgr() {
+        let a = event(LogicalKey::Char('a'), 0x41, Some(VC_A));
+        assert_eq!(input(with(a, ALTGR)), AltGrKey(VC_A));
+        let shift_altgr = Modifiers {
+            shift: true,
+            ..ALTGR
+        };
+        assert_eq!(input(with(a, shift_altgr)), ShiftAltGr(VC_A));
+    }
+
+    #[test]
+    fn shortcuts() {
+        let c = event(LogicalKey::Char('c'), 0x43, Some(VC_C));
+        assert_eq!(input(with(c, CTRL)), Unknown(LogicalKey::Char('c')));
+        // global actions are hotkeys, they don't come through here
+        let u = event(LogicalKey::Char('u'), 0x55, Some(VC_U));
+        let ctrl_shift = Modifiers {
+            shift: true,
+            ..CTRL
+        };
+        assert_eq!(input(with(u, ctrl_shift)), Unknown(LogicalKey::Char('u')));
+    }
+
+    #[test]
+    fn named_keys() {
+        let backspace = event(LogicalKey::Backspace, 0x08, None);
+        assert_eq!(input(backspace), Backspace(false));
+        assert_eq!(input(with(backspace, CTRL)), Backspace(true));
+        let enter = event(LogicalKey::Enter, 0x0D, None);
+        assert_eq!(input(with(enter, SHIFT)), Enter(true));
+        let left = event(LogicalKey::Left, 0x25, None);
+        assert_eq!(input(with(left, CTRL)), Left(true));
+        let shift = event(LogicalKey::Modifier(Modifier::Shift), 0x10, None);
+        assert_eq!(input(shift), Unknown(LogicalKey::Modifier(Modifier::Shift)));
+    }
+
+    #[test]
+    fn bound_keys() {
+        assert_eq!(
+            input(event(LogicalKey::Tab, 0x09, None)),
+            Bound(Action::NextCandidate)
+        );
+        assert_eq!(
+            input(event(LogicalKey::Escape, 0x1B, None)),
+            Bound(Action::Cancel)
+        );
+        // a binding takes precedence over what the key types
+        let bound = keymap(&[(Action::Commit, "Ctrl+J")]);
+        let j = with(event(LogicalKey::Char('j'), 0x4A, Some(VC_J)), CTRL);
+        assert_eq!(Input::from_event(&j, &bound), Bound(Action::Commit));
+        // unbound
+        let unbound = keymap(&[(Action::NextCandidate, "Down")]);
+        let tab = event(LogicalKey::Tab, 0x09, None);
+        assert_eq!(Input::from_event(&tab, &unbound), Tab);
+    }
+
+    #[test]
+    fn taps() {
+        let shift = Tap(Modifier::Shift);
+        assert_eq!(decide_default(shift, COMPOSING), Decision::Pass);
+        let keymap = keymap(&[(Action::ToggleMode, "Shift"), (Action::Commit, "Ctrl")]);
+        assert_eq!(
+            decide(shift, &IDLE, &keymap),
+            Decision::Hotkey(Action::ToggleMode)
+        );
+        let ctrl = Tap(Modifier::Ctrl);
+        assert_eq!(decide(ctrl, &IDLE, &keymap), Decision::Pass);
+        assert_eq!(
+            decide(ctrl, &COMPOSING, &keymap),
+            Decision::Action(Action::Commit)
+        );
+    }
+
+    #[test]
+    fn starting() {
+        assert_eq!(decide_default(Key(VC_A), IDLE), Decision::Type(VC_A, 0));
+        assert_eq!(
+            decide_default(ShiftAltGr(VC_A), IDLE),
+            Decision::Type(VC_A, MODIFIER_SHIFT ^ MODIFIER_ALT_GR)
+        );
+        assert_eq!(decide_default(Number(5), IDLE), Decision::Type(VC_5, 0));
+        assert_eq!(decide_default(Keypad(VC_KP_5), IDLE), Decision::Pass);
+        let number_pad = State {
+            number_pad: true,
+            ..IDLE
+        };
+        assert_eq!(
+            decide_default(Keypad(VC_KP_5), number_pad),
+            Decision::Type(VC_KP_5, 0)
+        );
+        for input in [
+            Space,
+            Enter(false),
+            Backspace(false),
+            Home,
+            Bound(Action::Cancel),
+        ] {
+            assert_eq!(decide_default(input, IDLE), Decision::Pass, "{input:?}");
+        }
+    }
+
+    #[test]
+    fn english() {
+        let english = State {
+            english: true,
+            ..IDLE
+        };
+        assert_eq!(decide_default(Key(VC_A), english), Decision::Pass);
+        // a composition left from before goes on
+        let composing = State {
+            english: true,
+            ..COMPOSING
+        };
+        assert_eq!(
+            decide_default(Key(VC_A), composing),
+            Decision::Type(VC_A, 0)
+        );
+    }
+
+    #[test]
+    fn preview() {
+        let previewing = State {
+            previewing: true,
+            ..IDLE
+        };
+        for input in [Space, Enter(true), Number(1), Bound(Action::Commit)] {
+            assert_eq!(
+                decide_default(input, previewing),
+                Decision::ConfirmPreview,
+                "{input:?}"
+            );
+        }
+        assert_eq!(decide_default(Escape, previewing), Decision::CancelPreview);
+        // anything else goes on as usual
+        assert_eq!(
+            decide_default(Key(VC_A), previewing),
+            Decision::Type(VC_A, 0)
+        );
+        assert_eq!(
+            decide_default(Number(2), previewing),
+            decide_default(Number(2), IDLE)
+        );
+    }
+
+    #[test]
+    fn undo() {
+        let can_undo = State {
+            can_undo: true,
+            ..IDLE
+        };
+        assert_eq!(
+            decide_default(Backspace(false), can_undo),
+            Decision::UndoCommit
+        );
+        assert_eq!(decide_default(Backspace(true), can_undo), Decision::Pass);
+        assert_eq!(decide_default(Backspace(false), IDLE), Decision::Pass);
+    }
+
+    #[test]
+    fn candidates() {
+        assert_eq!(decide_default(Number(3), COMPOSING), Decision::Select(2));
+        assert_eq!(decide_default(Number(0), COMPOSING), Decision::Ignore);
+        let no_candidates = State {
+            has_candidates: false,
+            ..COMPOSING
+        };
+        assert_eq!(
+            decide_default(Number(5), no_candidates),
+            Decision::Type(VC_5, 0)
+        );
+        assert_eq!(
+            decide_default(Left(false), COMPOSING),
+            Decision::Action(Action::PrevCandidate)
+        );
+        assert_eq!(
+            decide_default(Bound(Action::NextPage), COMPOSING),
+            Decision::Action(Action::NextPage)
+        );
+    }
+
+    #[test]
+    fn composing() {
+        assert_eq!(
+            decide_default(Space, COMPOSING),
+            Decision::Commit(Some(' '))
+        );
+        assert_eq!(
+            decide_default(Enter(true), COMPOSING),
+            Decision::Enter(true)
+        );
+        assert_eq!(
+            decide_default(Backspace(true), COMPOSING),
+            Decision::Pop(true)
+        );
+        assert_eq!(
+            decide_default(Keypad(VC_KP_5), COMPOSING),
+            Decision::CommitAndPass
+        );
+        // not bound to anything
+        assert_eq!(decide_default(Up, COMPOSING), Decision::Pass);
+    }
+
+    #[test]
+    fn caret() {
+        assert_eq!(decide_default(Home, COMPOSING), Decision::SetCaret(0));
+        assert_eq!(
+            decide_default(End, COMPOSING),
+            Decision::SetCaret(usize::MAX)
+        );
+        assert_eq!(decide_default(Delete, COMPOSING), Decision::Delete);
+        assert_eq!(
+            decide_default(Left(true), COMPOSING),
+            Decision::MoveCaret(-1)
+        );
+        let vertical = State {
+            vertical_candidates: true,
+            ..COMPOSING
+        };
+        assert_eq!(
+            decide_default(Right(false), vertical),
+            Decision::MoveCaret(1)
+        );
+        let not_editable = State {
+            preedit_editable: false,
+            ..COMPOSING
+        };
+        for input in [Home, End, Delete] {
+            assert_eq!(
+                decide_default(input, not_editable),
+                Decision::Pass,
+                "{input:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,479 @@
+use std::fmt;
+
+use log::warn;
+
+use crate::tap::Modifier;
+
+//----------------------------------------------------------------------------
+//
+//  Key bindings. Actions are bound to chords of a key and modifiers, read
+//  from `settings\Keys` as comma separated lists such as "Tab, Down, Ctrl+N".
+//  An action not in the settings keeps its default chords, and an empty
+//  list unbinds it. Keys are virtual-key codes, named as on the keyboard.
+//  Shift or Ctrl on its own binds a tap of it, see tap.rs.
+//
+//----------------------------------------------------------------------------
+
+/// What a chord can be bound to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Commit the highlighted candidate.
+    Commit,
+    /// Commit the roman text as it was typed.
+    CommitRaw,
+    NextCandidate,
+    PrevCandidate,
+    NextPage,
+    PrevPage,
+    /// Drop the composition, leaving nothing behind.
+    Cancel,
+    /// Switch between Bangla and English.
+    ToggleMode,
+    /// Switch between the layout in use and the alternate one.
+    SwitchLayout,
+    /// Take the last commit back into the composition.
+    UndoCommit,
+    /// Reopen the committed word at the caret as a composition.
+    Reconversion,
+    /// Transliterate the selected roman text.
+    Transliteration,
+}
+
+impl Action {
+    pub const ALL: [Action; 12] = [
+        Action::Commit,
+        Action::CommitRaw,
+        Action::NextCandidate,
+        Action::PrevCandidate,
+        Action::NextPage,
+        Action::PrevPage,
+        Action::Cancel,
+        Action::ToggleMode,
+        Action::SwitchLayout,
+        Action::UndoCommit,
+        Action::Reconversion,
+        Action::Transliteration,
+    ];
+
+    /// The name of the action in the settings.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Commit => "Commit",
+            Action::CommitRaw => "CommitRaw",
+            Action::NextCandidate => "NextCandidate",
+            Action::PrevCandidate => "PrevCandidate",
+            Action::NextPage => "NextPage",
+            Action::PrevPage => "PrevPage",
+            Action::Cancel => "Cancel",
+            Action::ToggleMode => "ToggleMode",
+            Action::SwitchLayout => "SwitchLayout",
+            Action::UndoCommit => "UndoCommit",
+            Action::Reconversion => "Reconversion",
+            Action::Transliteration => "Transliteration",
+        }
+    }
+
+    /// Global actions work whether composing or not. The others only act on a composition.
+    pub fn is_global(self) -> bool {
+        matches!(
+            self,
+            Action::ToggleMode
+                | Action::SwitchLayout
+                | Action::UndoCommit
+                | Action::Reconversion
+                | Action::Transliteration
+        )
+    }
+
+    fn default_chords(self) -> &'static str {
+        match self {
+            Action::NextCandidate => "Tab, Down",
+            Action::PrevCandidate => "Shift+Tab, Up",
+            Action::NextPage => "PageDown",
+            Action::PrevPage => "PageUp",
+            Action::Cancel => "Escape",
+            Action::Reconversion => "Ctrl+Shift+Backspace",
+            Action::Transliteration => "Ctrl+Shift+U",
+            // Alt+Backspace would take undo away from applications
+            Action::Commit
+            | Action::CommitRaw
+            | Action::ToggleMode
+            | Action::SwitchLayout
+            | Action::UndoCommit => "",
+        }
+    }
+}
+
+/// Named keys and their virtual-key codes. Letters, digits and F1-F24 are named as they are.
+const KEY_NAMES: [(&str, u32); 28] = [
+    ("Backspace", 0x08),
+    ("Tab", 0x09),
+    ("Enter", 0x0D),
+    ("Shift", 0x10),
+    ("Ctrl", 0x11),
+    ("Escape", 0x1B),
+    ("Space", 0x20),
+    ("PageUp", 0x21),
+    ("PageDown", 0x22),
+    ("End", 0x23),
+    ("Home", 0x24),
+    ("Left", 0x25),
+    ("Up", 0x26),
+    ("Right", 0x27),
+    ("Down", 0x28),
+    ("Insert", 0x2D),
+    ("Delete", 0x2E),
+    (";", 0xBA),
+    ("=", 0xBB),
+    (",", 0xBC),
+    ("-", 0xBD),
+    (".", 0xBE),
+    ("/", 0xBF),
+    ("`", 0xC0),
+    ("[", 0xDB),
+    ("\\", 0xDC),
+    ("]", 0xDD),
+    ("'", 0xDE),
+];
+
+/// A key pressed along with modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chord {
+    pub key: u32,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl Chord {
+    /// Parse a chord such as "Ctrl+Shift+U". Modifiers come first, in any order.
+    pub fn parse(text: &str) -> Option<Chord> {
+        let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        // "Ctrl++" binds the plus key, which is the equals key
+        if text.trim_end().ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("=");
+        }
+        let (key, modifiers) = parts.split_last()?;
+        let mut chord = Chord {
+            key: key_code(key)?,
+            ctrl: false,
+            shift: false,
+            alt: false,
+        };
+        for modifier in modifiers {
+            let held = match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => &mut chord.ctrl,
+                "shift" => &mut chord.shift,
+                "alt" => &mut chord.alt,
+                _ => return None,
+            };
+            *held = true;
+        }
+        // a tap is of the modifier alone
+        if chord.is_tap() && (chord.ctrl || chord.shift || chord.alt) {
+            return None;
+        }
+        Some(chord)
+    }
+
+    /// Whether the chord is a modifier tapped on its own.
+    pub fn is_tap(&self) -> bool {
+        Modifier::from_vkey(self.key).is_some()
+    }
+
+    /// Chords that type a character, which can't be taken away from typing.
+    fn types_character(&self) -> bool {
+        let printable = self.key == 0x20
+            || (0x30..=0x39).contains(&self.key)
+            || (0x41..=0x5A).contains(&self.key)
+            || (0xBA..=0xDE).contains(&self.key);
+        printable && !self.ctrl && !self.alt
+    }
+}
+
+fn key_code(name: &str) -> Option<u32> {
+    if let Some(&(_, code)) = KEY_NAMES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+    {
+        return Some(code);
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch @ ('a'..='z' | 'A'..='Z' | '0'..='9')), None) => {
+            Some(ch.to_ascii_uppercase() as u32)
+        }
+        (Some('F' | 'f'), Some(_)) => match name[1..].parse::<u32>() {
+            Ok(n @ 1..=24) => Some(0x70 + n - 1),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (held, name) in [
+            (self.ctrl, "Ctrl+"),
+            (self.shift, "Shift+"),
+            (self.alt, "Alt+"),
+        ] {
+            if held {
+                f.write_str(name)?;
+            }
+        }
+        match KEY_NAMES.iter().find(|(_, code)| *code == self.key) {
+            Some((name, _)) => f.write_str(name),
+            None if (0x70..0x88).contains(&self.key) => write!(f, "F{}", self.key - 0x70 + 1),
+            None => match char::from_u32(self.key) {
+                Some(ch) if ch.is_ascii_alphanumeric() => write!(f, "{ch}"),
+                _ => write!(f, "{:#04X}", self.key),
+            },
+        }
+    }
+}
+
+/// Chords and the actions they are bound to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    bindings: Vec<(Chord, Action)>,
+}
+
+impl Keymap {
+    /// Bind each action to the chords `configured` gives for it, or to its default ones if
+    /// it gives None. Chords that can't be parsed, are bound already or would take a key away
+    /// from typing are reported and skipped.
+    pub fn load(mut configured: impl FnMut(Action) -> Option<String>) -> Keymap {
+        let mut bindings: Vec<(Chord, Action)> = Vec::new();
+        for action in Action::ALL {
+            let chords = configured(action).unwrap_or_else(|| action.default_chords().to_string());
+            for text in chords
+                .split(',')
+                .map(str::trim)
+                .filter(|text| !text.is_empty())
+            {
+                let Some(chord) = Chord::parse(text) else {
+                    warn!("Can't bind {} to '{text}', no such key.", action.name());
+                    continue;
+                };
+                if let Some((_, bound)) = bindings.iter().find(|(other, _)| *other == chord) {
+                    warn!(
+                        "Can't bind {} to {chord}, it's bound to {} already.",
+                        action.name(),
+                        bound.name()
+                    );
+                } else if chord.types_character() {
+                    warn!("Can't bind {} to {chord}, it's for typing.", action.name());
+                } else if chord.alt && !action.is_global() {
+                    // Alt chords come as system keys, which are only seen as hotkeys
+                    warn!(
+                        "Can't bind {} to {chord}, Alt only works for hotkeys.",
+                        action.name()
+                    );
+                } else {
+                    bindings.push((chord, action));
+                }
+            }
+        }
+        Keymap { bindings }
+    }
+
+    pub fn action(&self, chord: Chord) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(bound, _)| *bound == chord)
+            .map(|&(_, action)| action)
+    }
+
+    /// Every chord and the action it's bound to, in a stable order.
+    pub fn bindings(&self) -> &[(Chord, Action)] {
+        &self.bindings
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::load(|_| None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(key: u32, ctrl: bool, shift: bool, alt: bool) -> Chord {
+        Chord {
+            key,
+            ctrl,
+            shift,
+            alt,
+        }
+    }
+
+    fn keymap(settings: &[(Action, &str)]) -> Keymap {
+        Keymap::load(|action| {
+            settings
+                .iter()
+                .find(|(configured, _)| *configured == action)
+                .map(|(_, chords)| chords.to_string())
+        })
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            Chord::parse("Ctrl+Shift+U"),
+            Some(chord(0x55, true, true, false))
+        );
+        assert_eq!(
+            Chord::parse(" shift + alt + f12 "),
+            Some(chord(0x7B, false, true, true))
+        );
+        assert_eq!(
+            Chord::parse("PageDown"),
+            Some(chord(0x22, false, false, false))
+        );
+        assert_eq!(
+            Chord::parse("Ctrl++"),
+            Some(chord(0xBB, true, false, false))
+        );
+        assert_eq!(Chord::parse("Ctrl+="), Chord::parse("Ctrl++"));
+        assert_eq!(
+            Chord::parse("Shift"),
+            Some(chord(0x10, false, false, false))
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(Chord::parse(""), None);
+        assert_eq!(Chord::parse("Hyper+U"), None);
+        assert_eq!(Chord::parse("Ctrl+Enterr"), None);
+        assert_eq!(Chord::parse("F25"), None);
+        // a tap is of the modifier alone
+        assert_eq!(Chord::parse("Ctrl+Shift"), None);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for text in [
+            "Ctrl+Shift+U",
+            "Alt+F4",
+            "Shift+Tab",
+            "Ctrl+=",
+            "Ctrl+Shift+Backspace",
+        ] {
+            let chord = Chord::parse(text).unwrap();
+            assert_eq!(chord.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let keymap = Keymap::default();
+        assert_eq!(
+            keymap.action(chord(0x09, false, false, false)),
+            Some(Action::NextCandidate)
+        );
+        assert_eq!(
+            keymap.action(chord(0x55, true, true, false)),
+            Some(Action::Transliteration)
+        );
+        // Alt+Backspace is the undo of applications
+        assert_eq!(keymap.action(chord(0x08, false, false, true)), None);
+        assert!(
+            keymap
+                .bindings()
+                .iter()
+                .all(|&(_, action)| action != Action::UndoCommit)
+        );
+    }
+
+    #[test]
+    fn duplicate_chord_rejected() {
+        let keymap = keymap(&[
+            (Action::Commit, "Tab"),
+            (Action::UndoCommit, "Ctrl+Z, Ctrl+Z"),
+        ]);
+        // Commit comes first and takes Tab from the default chords of NextCandidate
+        assert_eq!(
+            keymap.action(chord(0x09, false, false, false)),
+            Some(Action::Commit)
+        );
+        assert_eq!(
+            keymap.action(chord(0x28, false, false, false)),
+            Some(Action::NextCandidate)
+        );
+        let undo = chord(0x5A, true, false, false);
+        assert_eq!(
+            keymap
+                .bindings()
+                .iter()
+                .filter(|&&(bound, _)| bound == undo)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn typing_key_rejected() {
+        let keymap = keymap(&[
+            (Action::Commit, "Space, Shift+A, ;, Enter"),
+            (Action::ToggleMode, "Ctrl+Space"),
+        ]);
+        assert_eq!(keymap.action(chord(0x20, false, false, false)), None);
+        assert_eq!(keymap.action(chord(0x41, false, true, false)), None);
+        assert_eq!(keymap.action(chord(0xBA, false, false, false)), None);
+        assert_eq!(
+            keymap.action(chord(0x0D, false, false, false)),
+            Some(Action::Commit)
+        );
+        assert_eq!(
+            keymap.action(chord(0x20, true, false, false)),
+            Some(Action::ToggleMode)
+        );
+    }
+
+    #[test]
+    fn alt_only_for_global_actions() {
+        let keymap = keymap(&[(Action::Cancel, "Alt+Q"), (Action::ToggleMode, "Alt+M")]);
+        assert_eq!(keymap.action(chord(0x51, false, false, true)), None);
+        assert_eq!(
+            keymap.action(chord(0x4D, false, false, true)),
+            Some(Action::ToggleMode)
+        );
+    }
+
+    #[test]
+    fn empty_list_unbinds() {
+        let keymap = keymap(&[(Action::NextCandidate, ""), (Action::Cancel, " , ")]);
+        assert_eq!(keymap.action(chord(0x09, false, false, false)), None);
+        assert_eq!(keymap.action(chord(0x28, false, false, false)), None);
+        assert_eq!(keymap.action(chord(0x1B, false, false, false)), None);
+        // the others keep their defaults
+        assert_eq!(
+            keymap.action(chord(0x26, false, false, false)),
+            Some(Action::PrevCandidate)
+        );
+    }
+
+    #[test]
+    fn unknown_key_skipped() {
+        let keymap = keymap(&[(Action::Cancel, "Esc, Escape")]);
+        let bound: Vec<_> = keymap
+            .bindings()
+            .iter()
+            .filter(|&&(_, action)| action == Action::Cancel)
+            .collect();
+        assert_eq!(bound, [&(chord(0x1B, false, false, false), Action::Cancel)]);
+    }
+
+    #[test]
+    fn taps() {
+        let keymap = keymap(&[(Action::ToggleMode, "Shift")]);
+        let tap = chord(0x10, false, false, false);
+        assert!(tap.is_tap());
+        assert_eq!(keymap.action(tap), Some(Action::ToggleMode));
+    }
+}
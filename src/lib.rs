@@ -1,9 +1,16 @@
 #![allow(non_camel_case_types)]
 mod conf;
+mod dict;
+mod emoji;
 mod extend;
 mod global;
+mod key_event;
+mod keymap;
 mod logger;
 mod register;
+mod romanize;
+mod surrounding;
+mod tap;
 mod tsf;
 mod ui;
 
@@ -149,14 +156,16 @@ pub enum Error {
     #[error(transparent)]
     Var(#[from] std::env::VarError),
     // custom ones
-    #[error("Language ID is missing from 'install.toml'.")]
+    #[error("Language ID is missing from the keyboard layout.")]
     LangidMissing,
-    #[error("Keyboad layout is is missing from 'install.toml'.")]
+    #[error("Keyboard layout is missing from 'install.dat'.")]
     LayoutMissing,
-    #[error("Requested keyboard layout is invalid.")]
+    #[error("Requested keyboard layout is not installed.")]
     LayoutInvalid,
-    #[error("install.dat is corrupted. {0}")]
+    #[error("Keyboard layout in 'install.dat' is not a hexadecimal HKL. {0}")]
     InstallDatCorrupted(ParseIntError),
+    #[error("Keyboard layout in the settings is not a hexadecimal HKL. {0}")]
+    KeyboardLayoutCorrupted(ParseIntError),
 }
 
 // bonus From<E> for alternative windows Error types
@@ -5,7 +5,7 @@ use windows::{
     Win32::{
         System::Com::{CLSCTX_INPROC_SERVER, CoCreateInstance},
         UI::TextServices::{
-            self, CLSID_TF_CategoryMgr, CLSID_TF_InputProcessorProfiles, HKL, ITfCategoryMgr,
+            self, CLSID_TF_CategoryMgr, CLSID_TF_InputProcessorProfiles, ITfCategoryMgr,
             ITfInputProcessorProfileMgr,
         },
     },
@@ -82,7 +82,7 @@ pub fn register_ime() -> Result<()> {
             &ime_name,
             &icon_file,
             ICON_INDEX,
-            HKL::default(),
+            hkl().unwrap_or_default(),
             0,
             true,
             0,
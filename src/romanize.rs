@@ -0,0 +1,207 @@
+//----------------------------------------------------------------------------
+//
+//  Reverse transliteration. Committed Bangla text whose roman input is not
+//  remembered is turned back into something riti can type, following the
+//  phonetic (Avro) conventions. The result doesn't have to be exactly what
+//  the user typed, only close enough for riti to suggest the word again.
+//
+//----------------------------------------------------------------------------
+
+const HASANTA: char = '\u{09CD}';
+const NUKTA: char = '\u{09BC}';
+
+/// Whether `ch` belongs to a Bangla word: letters, signs and digits of the Bangla block
+/// plus the joiners used to shape conjuncts.
+pub fn is_bangla(ch: char) -> bool {
+    matches!(ch, '\u{0981}'..='\u{09FE}' | '\u{200C}' | '\u{200D}')
+}
+
+/// Phonetic roman spelling of a Bangla word. Characters without a spelling are dropped.
+pub fn romanize(bangla: &str) -> String {
+    let chars = compose_nukta(bangla);
+    let mut roman = String::with_capacity(chars.len() * 2);
+    let mut iter = chars.iter().copied().peekable();
+    while let Some(ch) = iter.next() {
+        if ch == HASANTA {
+            // a Ya after Hasanta is the Ya-phala
+            if iter.next_if_eq(&'য').is_some() {
+                roman.push('y');
+            }
+            continue;
+        }
+        let Some(spelling) = spelling(ch) else {
+            continue;
+        };
+        roman.push_str(spelling);
+        // consonants carry an inherent vowel which has to be spelled out unless a
+        // vowel sign or Hasanta follows, or the word ends
+        if is_consonant(ch)
+            && let Some(&next) = iter.peek()
+            && !is_vowel_sign(next)
+            && next != HASANTA
+            && is_bangla(next)
+        {
+            roman.push('o');
+        }
+    }
+    roman
+}
+
+/// Fold consonants written with a separate Nukta into their precomposed forms.
+fn compose_nukta(bangla: &str) -> Vec<char> {
+    let mut chars: Vec<char> = Vec::with_capacity(bangla.len());
+    for ch in bangla.chars() {
+        let composed = match (chars.last(), ch) {
+            (Some('ড'), NUKTA) => '\u{09DC}',
+            (Some('ঢ'), NUKTA) => '\u{09DD}',
+            (Some('য'), NUKTA) => '\u{09DF}',
+            _ => {
+                chars.push(ch);
+                continue;
+            }
+        };
+        *chars.last_mut().unwrap() = composed;
+    }
+    chars
+}
+
+fn is_consonant(ch: char) -> bool {
+    matches!(ch, 'ক'..='ন' | 'প'..='র' | 'ল' | 'শ'..='হ' | '\u{09DC}' | '\u{09DD}' | '\u{09DF}')
+}
+
+fn is_vowel_sign(ch: char) -> bool {
+    matches!(ch, '\u{09BE}'..='\u{09CC}' | '\u{09D7}')
+}
+
+fn spelling(ch: char) -> Option<&'static str> {
+    let spelling = match ch {
+        // vowels
+        'অ' => "o",
+        'আ' => "a",
+        'ই' => "i",
+        'ঈ' => "I",
+        'উ' => "u",
+        'ঊ' => "U",
+        'ঋ' => "rri",
+        'এ' => "e",
+        'ঐ' => "OI",
+        'ও' => "O",
+        'ঔ' => "OU",
+        // vowel signs
+        'া' => "a",
+        'ি' => "i",
+        'ী' => "I",
+        'ু' => "u",
+        'ূ' => "U",
+        'ৃ' => "rri",
+        'ে' => "e",
+        'ৈ' => "OI",
+        'ো' => "O",
+        'ৌ' | 'ৗ' => "OU",
+        // consonants
+        'ক' => "k",
+        'খ' => "kh",
+        'গ' => "g",
+        'ঘ' => "gh",
+        'ঙ' => "Ng",
+        'চ' => "c",
+        'ছ' => "ch",
+        'জ' => "j",
+        'ঝ' => "jh",
+        'ঞ' => "NG",
+        'ট' => "T",
+        'ঠ' => "Th",
+        'ড' => "D",
+        'ঢ' => "Dh",
+        'ণ' => "N",
+        'ত' => "t",
+        'থ' => "th",
+        'দ' => "d",
+        'ধ' => "dh",
+        'ন' => "n",
+        'প' => "p",
+        'ফ' => "f",
+        'ব' => "b",
+        'ভ' => "v",
+        'ম' => "m",
+        'য' => "z",
+        'র' => "r",
+        'ল' => "l",
+        'শ' => "S",
+        'ষ' => "Sh",
+        'স' => "s",
+        'হ' => "h",
+        '\u{09DC}' => "R",
+        '\u{09DD}' => "Rh",
+        '\u{09DF}' => "y",
+        // signs
+        'ৎ' => "t``",
+        'ং' => "ng",
+        'ঃ' => ":",
+        'ঁ' => "^",
+        // digits
+        '০' => "0",
+        '১' => "1",
+        '২' => "2",
+        '৩' => "3",
+        '৪' => "4",
+        '৫' => "5",
+        '৬' => "6",
+        '৭' => "7",
+        '৮' => "8",
+        '৯' => "9",
+        _ => return None,
+    };
+    Some(spelling)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words() {
+        assert_eq!(romanize("আমি"), "ami");
+        assert_eq!(romanize("আমার"), "amar");
+        assert_eq!(romanize("বাংলা"), "bangla");
+        assert_eq!(romanize("ভাষা"), "vaSha");
+    }
+
+    #[test]
+    fn inherent_vowel() {
+        assert_eq!(romanize("কথা"), "kotha");
+        assert_eq!(romanize("কলম"), "kolom");
+        assert_eq!(romanize("ক"), "k");
+    }
+
+    #[test]
+    fn conjuncts() {
+        assert_eq!(romanize("পক্ষ"), "pokSh");
+        assert_eq!(romanize("বাক্য"), "baky");
+        assert_eq!(romanize("ক্যা"), "kya");
+    }
+
+    #[test]
+    fn nukta() {
+        assert_eq!(romanize("বাড়ি"), "baRi");
+        assert_eq!(romanize("বাড\u{09BC}ি"), "baRi");
+        assert_eq!(romanize("য\u{09BC}"), "y");
+    }
+
+    #[test]
+    fn signs_and_digits() {
+        assert_eq!(romanize("দুঃখ"), "du:kh");
+        assert_eq!(romanize("চাঁদ"), "ca^d");
+        assert_eq!(romanize("হঠাৎ"), "hoThat``");
+        assert_eq!(romanize("১৯৭১"), "1971");
+    }
+
+    #[test]
+    fn foreign_characters_dropped() {
+        assert_eq!(romanize("আমি, তুমি"), "amitumi");
+        assert!(is_bangla('ক'));
+        assert!(is_bangla('\u{200C}'));
+        assert!(!is_bangla('k'));
+        assert!(!is_bangla('।'));
+    }
+}
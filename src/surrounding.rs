@@ -0,0 +1,177 @@
+use riti::config::Config;
+
+//----------------------------------------------------------------------------
+//
+//  Surrounding text. riti only ever sees the keys of the current word, so the
+//  text before the caret is looked at to tell a few things about where the
+//  word is being typed, which riti is configured with as the word starts.
+//  Documents are abstracted away so that the hints can be worked out the same
+//  way for a TSF context or a plain string.
+//
+//----------------------------------------------------------------------------
+
+/// How much text before the caret is looked at, in UTF-16 units.
+pub const WINDOW_LEN: usize = 32;
+
+const HASANTA: char = '\u{09CD}';
+const SENTENCE_ENDS: [char; 5] = ['.', '!', '?', '।', '॥'];
+
+/// Somewhere text is typed into.
+pub trait Document {
+    /// Up to `max` UTF-16 units of text right before the caret. None if it can't be read.
+    fn text_before_caret(&self, max: usize) -> Option<String>;
+}
+
+/// A string standing for a document with the caret at its end.
+impl Document for str {
+    fn text_before_caret(&self, max: usize) -> Option<String> {
+        let mut len = 0;
+        let start = self
+            .char_indices()
+            .rev()
+            .take_while(|(_, ch)| {
+                len += ch.len_utf16();
+                len <= max
+            })
+            .last()
+            .map_or(self.len(), |(index, _)| index);
+        Some(self[start..].to_string())
+    }
+}
+
+/// What the text before the caret tells about the word about to be typed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Hints {
+    /// Right after a Hasanta, i.e. in the middle of a conjunct.
+    pub after_hasanta: bool,
+    /// Right after a roman letter, i.e. in the middle of an English word.
+    pub in_english_word: bool,
+    /// At the start of a sentence, a line or the document.
+    pub sentence_start: bool,
+}
+
+impl Hints {
+    /// Read the hints off `document`. Nothing is assumed if it can't be read.
+    pub fn from_document<D: Document + ?Sized>(document: &D) -> Hints {
+        match document.text_before_caret(WINDOW_LEN) {
+            Some(text) => Hints::from_text(&text, text.encode_utf16().count() < WINDOW_LEN),
+            None => Hints::default(),
+        }
+    }
+
+    /// `whole` tells whether `text` goes back to the start of the document.
+    fn from_text(text: &str, whole: bool) -> Hints {
+        let last = text.chars().next_back();
+        let sentence_start = match text.trim_end_matches([' ', '\t']).chars().next_back() {
+            None => whole,
+            Some(_) if text.ends_with(['\n', '\r']) => true,
+            Some(ch) => SENTENCE_ENDS.contains(&ch) && last != Some(ch),
+        };
+        Hints {
+            after_hasanta: last == Some(HASANTA),
+            in_english_word: last.is_some_and(|ch| ch.is_ascii_alphabetic()),
+            sentence_start,
+        }
+    }
+
+    /// Configure riti for the word. One going on with an English word gets English
+    /// suggestions whatever the settings say.
+    pub fn configure(&self, config: &mut Config) {
+        if self.in_english_word {
+            config.set_suggestion_include_english(true);
+        }
+    }
+
+    /// Capitalize English suggestions at the start of a sentence.
+    pub fn apply(&self, suggestion: String) -> String {
+        let mut chars = suggestion.chars();
+        match chars.next() {
+            Some(first) if self.sentence_start && first.is_ascii_lowercase() => {
+                first.to_ascii_uppercase().to_string() + chars.as_str()
+            }
+            _ => suggestion,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// A document that can't be read, remembering how much was asked for.
+    struct Unreadable(Cell<usize>);
+
+    impl Document for Unreadable {
+        fn text_before_caret(&self, max: usize) -> Option<String> {
+            self.0.set(max);
+            None
+        }
+    }
+
+    fn hints(text: &str) -> Hints {
+        Hints::from_document(text)
+    }
+
+    #[test]
+    fn after_hasanta() {
+        assert!(hints("ক্").after_hasanta);
+        assert!(!hints("ক").after_hasanta);
+        assert!(!hints("ক্ ").after_hasanta);
+    }
+
+    #[test]
+    fn in_english_word() {
+        assert!(hints("hello").in_english_word);
+        assert!(hints("আমি hello").in_english_word);
+        assert!(!hints("hello ").in_english_word);
+        assert!(!hints("hello1").in_english_word);
+        assert!(!hints("আমি").in_english_word);
+    }
+
+    #[test]
+    fn sentence_start() {
+        assert!(hints("").sentence_start);
+        assert!(hints("Hello. ").sentence_start);
+        assert!(hints("আমি। ").sentence_start);
+        assert!(hints("Hello\n").sentence_start);
+        assert!(hints("Hello.\r\n").sentence_start);
+        assert!(!hints("Hello.").sentence_start);
+        assert!(!hints("Hello ").sentence_start);
+        assert!(hints("   ").sentence_start);
+    }
+
+    #[test]
+    fn beyond_window() {
+        let long = "a".repeat(WINDOW_LEN * 2);
+        assert!(hints(&(long.clone() + ". ")).sentence_start);
+        // the start of the document is out of sight
+        assert!(!hints(&" ".repeat(WINDOW_LEN * 2)).sentence_start);
+        assert!(hints(&" ".repeat(WINDOW_LEN - 1)).sentence_start);
+    }
+
+    #[test]
+    fn unreadable_document() {
+        let document = Unreadable(Cell::new(0));
+        assert_eq!(Hints::from_document(&document), Hints::default());
+        assert_eq!(document.0.get(), WINDOW_LEN);
+    }
+
+    #[test]
+    fn text_before_caret_of_str() {
+        assert_eq!("abc".text_before_caret(2).unwrap(), "bc");
+        assert_eq!("abc".text_before_caret(8).unwrap(), "abc");
+        // a character that doesn't fit as a whole is left out
+        assert_eq!("a😀".text_before_caret(1).unwrap(), "");
+        assert_eq!("a😀".text_before_caret(2).unwrap(), "😀");
+    }
+
+    #[test]
+    fn capitalization() {
+        let start = hints("");
+        assert_eq!(start.apply("hello".to_string()), "Hello");
+        assert_eq!(start.apply("আমি".to_string()), "আমি");
+        assert_eq!(hints("a ").apply("hello".to_string()), "hello");
+    }
+}
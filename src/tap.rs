@@ -0,0 +1,223 @@
+use std::time::{Duration, Instant};
+
+//----------------------------------------------------------------------------
+//
+//  Modifier taps. A modifier pressed and released on its own, quickly, is a
+//  tap rather than the start of a chord. The tracker is fed every key going
+//  down and up and tells the two apart: any other key pressed in between
+//  makes it a chord, and so does holding the modifier too long. Alt is left
+//  out as it never reaches the key event sink on its own.
+//
+//----------------------------------------------------------------------------
+
+/// How long a modifier may be held and still count as a tap.
+pub const TAP_TIMEOUT: Duration = Duration::from_millis(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modifier {
+    Shift,
+    Ctrl,
+}
+
+impl Modifier {
+    /// The modifier of a virtual-key code, either side of the keyboard.
+    pub fn from_vkey(vkey: u32) -> Option<Modifier> {
+        match vkey {
+            0x10 | 0xA0 | 0xA1 => Some(Modifier::Shift),
+            0x11 | 0xA2 | 0xA3 => Some(Modifier::Ctrl),
+            _ => None,
+        }
+    }
+
+    pub fn vkey(self) -> u32 {
+        match self {
+            Modifier::Shift => 0x10,
+            Modifier::Ctrl => 0x11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Idle,
+    /// A modifier is held on its own since then.
+    Pressed(Modifier, Instant),
+    /// Other keys went down while a modifier was held.
+    Chord,
+}
+
+#[derive(Debug, Default)]
+pub struct TapTracker {
+    state: State,
+}
+
+impl TapTracker {
+    /// A key went down. `repeat` if it was down already, i.e. auto-repeat.
+    pub fn key_down(&mut self, vkey: u32, repeat: bool, now: Instant) {
+        let modifier = Modifier::from_vkey(vkey);
+        self.state = match self.state {
+            // held down and repeating, still the same press
+            State::Pressed(pressed, _) if modifier == Some(pressed) && repeat => return,
+            // pressed anew, its release went unseen
+            State::Pressed(pressed, _) if modifier == Some(pressed) => State::Pressed(pressed, now),
+            // a modifier held since before a chord ended is not a press of its own
+            State::Idle => match modifier {
+                Some(modifier) if !repeat => State::Pressed(modifier, now),
+                _ => State::Idle,
+            },
+            State::Pressed(..) | State::Chord => State::Chord,
+        };
+    }
+
+    /// A key went down unseen by `key_down`, such as a preserved key.
+    pub fn interrupt(&mut self) {
+        if self.state != State::Idle {
+            self.state = State::Chord;
+        }
+    }
+
+    /// The modifier the release of `vkey` would tap, without releasing it.
+    pub fn peek(&self, vkey: u32, now: Instant) -> Option<Modifier> {
+        match self.state {
+            State::Pressed(pressed, since)
+                if Modifier::from_vkey(vkey) == Some(pressed)
+                    && now.saturating_duration_since(since) <= TAP_TIMEOUT =>
+            {
+                Some(pressed)
+            }
+            _ => None,
+        }
+    }
+
+    /// A key went up. Returns the modifier tapped, if it was a tap.
+    pub fn key_up(&mut self, vkey: u32, now: Instant) -> Option<Modifier> {
+        let tapped = self.peek(vkey, now);
+        self.state = match (self.state, Modifier::from_vkey(vkey)) {
+            // a chord is over once any of its modifiers is released
+            (_, Some(_)) => State::Idle,
+            (State::Idle, None) => State::Idle,
+            // a key held from before the modifier went down
+            (State::Pressed(..) | State::Chord, None) => State::Chord,
+        };
+        tapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT: u32 = 0x10;
+    const LSHIFT: u32 = 0xA0;
+    const CTRL: u32 = 0x11;
+    const A: u32 = 0x41;
+
+    fn after(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn tap() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(SHIFT, false, start);
+        assert_eq!(taps.peek(SHIFT, after(start, 100)), Some(Modifier::Shift));
+        assert_eq!(taps.key_up(SHIFT, after(start, 100)), Some(Modifier::Shift));
+        // over once released
+        assert_eq!(taps.key_up(SHIFT, after(start, 150)), None);
+    }
+
+    #[test]
+    fn either_side() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(LSHIFT, false, start);
+        assert_eq!(taps.key_up(LSHIFT, after(start, 50)), Some(Modifier::Shift));
+    }
+
+    #[test]
+    fn timed_out() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(CTRL, false, start);
+        let late = start + TAP_TIMEOUT + Duration::from_millis(1);
+        assert_eq!(taps.peek(CTRL, late), None);
+        assert_eq!(taps.key_up(CTRL, late), None);
+        // just in time
+        taps.key_down(CTRL, false, start);
+        assert_eq!(taps.key_up(CTRL, start + TAP_TIMEOUT), Some(Modifier::Ctrl));
+    }
+
+    #[test]
+    fn chord() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(SHIFT, false, start);
+        taps.key_down(A, false, after(start, 20));
+        assert_eq!(taps.key_up(A, after(start, 40)), None);
+        assert_eq!(taps.key_up(SHIFT, after(start, 60)), None);
+        // a tap again once the chord is over
+        taps.key_down(SHIFT, false, after(start, 80));
+        assert_eq!(taps.key_up(SHIFT, after(start, 100)), Some(Modifier::Shift));
+    }
+
+    #[test]
+    fn auto_repeat() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(SHIFT, false, start);
+        taps.key_down(SHIFT, true, after(start, 30));
+        taps.key_down(SHIFT, true, after(start, 60));
+        // still timed from the first press
+        assert_eq!(taps.peek(SHIFT, after(start, 250)), Some(Modifier::Shift));
+        taps.key_down(SHIFT, true, after(start, 280));
+        assert_eq!(taps.key_up(SHIFT, after(start, 400)), None);
+    }
+
+    #[test]
+    fn repeat_of_modifier_held_through_chord() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(SHIFT, false, start);
+        taps.key_down(A, false, after(start, 20));
+        taps.key_up(A, after(start, 40));
+        // Shift repeating after the chord is not a press of its own
+        taps.key_down(SHIFT, true, after(start, 60));
+        assert_eq!(taps.key_up(SHIFT, after(start, 80)), None);
+    }
+
+    #[test]
+    fn key_released_while_held() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        // A went down before Shift did
+        taps.key_down(SHIFT, false, start);
+        assert_eq!(taps.key_up(A, after(start, 20)), None);
+        assert_eq!(taps.key_up(SHIFT, after(start, 40)), None);
+    }
+
+    #[test]
+    fn other_modifier() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(CTRL, false, start);
+        taps.key_down(SHIFT, false, after(start, 20));
+        assert_eq!(taps.key_up(SHIFT, after(start, 40)), None);
+        assert_eq!(taps.key_up(CTRL, after(start, 60)), None);
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut taps = TapTracker::default();
+        let start = Instant::now();
+        taps.key_down(CTRL, false, start);
+        taps.interrupt();
+        assert_eq!(taps.peek(CTRL, after(start, 20)), None);
+        assert_eq!(taps.key_up(CTRL, after(start, 20)), None);
+        // nothing to interrupt
+        taps.interrupt();
+        taps.key_down(CTRL, false, after(start, 40));
+        assert_eq!(taps.key_up(CTRL, after(start, 60)), Some(Modifier::Ctrl));
+    }
+}
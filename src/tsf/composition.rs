@@ -1,16 +1,36 @@
-use std::ffi::OsString;
+use std::{ffi::OsString, ops::Range};
 
 use log::{debug, trace};
+use riti::suggestion::Suggestion;
 use windows::{
     Win32::{
         Foundation::E_FAIL,
-        UI::TextServices::{ITfComposition, ITfCompositionSink_Impl},
+        UI::TextServices::{ITfComposition, ITfCompositionSink_Impl, ITfContext, ITfRange},
     },
     core::Result,
 };
 
-use super::{TextService, TextServiceInner, edit_session};
-use crate::extend::OsStrExt2;
+use super::{
+    TextService, TextServiceInner,
+    display_attribute_provider::DisplayAttribute,
+    edit_queue::{Attributes, Composition, Target},
+    edit_session,
+    keycode::type_text,
+};
+use crate::{emoji::EmojiTable, extend::OsStrExt2, global::CANDI_NUM};
+
+/// A commit that can still be taken back into the composition it came from.
+pub struct LastCommit {
+    text: String,
+    range: ITfRange,
+    roman: String,
+    selected: usize,
+    // the space or new line that followed the candidate, if any
+    appended: Option<char>,
+}
+
+/// How many slots of the candidate list the emoji layer may take after riti's suggestions.
+const EMOJI_TRAILING_NUM: usize = 2;
 
 //----------------------------------------------------------------------------
 //
@@ -22,12 +42,19 @@ use crate::extend::OsStrExt2;
 
 impl TextServiceInner {
     // there are only two states: composing or not
-    pub fn start_composition(&mut self) -> Result<()> {
+    // compositions start at the selection, or over existing text to reconvert it
+    pub fn start_composition(&mut self, range: Option<&ITfRange>) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        let composition =
-            edit_session::start_composition(self.tid, self.context()?, &self.interface()?)?;
-        self.composition = Some(composition);
+        let composition = self.edits.start_composition(
+            self.tid,
+            self.context()?,
+            range.cloned(),
+            self.interface()?,
+        )?;
+        self.session.composition = Some(composition);
+        // a new composition gives up on the one put aside
+        self.session.suspended = None;
         if let Some((x, y)) = self.get_pos() {
             self.candidate_list()?.locate(x, y)?;
         }
@@ -39,41 +66,83 @@ impl TextServiceInner {
 
         // clean up the shit as clean as possbile instead of question-markin' all the way thru
         if let (Some(context), Some(composition)) =
-            (self.context.as_ref(), self.composition.as_ref())
+            (self.context.as_ref(), self.session.composition.as_ref())
         {
-            let _ = edit_session::end_composition(self.tid, context, composition);
+            let _ = self.edits.end_composition(self.tid, context, composition);
         }
         self.riti.finish_input_session();
-        self.composition = None;
-        self.preedit.clear();
-        self.suggestions = None;
+        self.session.composition = None;
+        self.session.preedit.clear();
+        self.session.caret = None;
+        self.session.suggestions = None;
+        self.session.candidates.clear();
+        self.session.riti_candidates = 0;
         self.candidate_list()?.hide();
         Ok(())
     }
 
     fn update_preedit(&mut self) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-        let range = unsafe { self.composition()?.GetRange()? };
-        let text = OsString::from(&self.preedit).to_wchars();
+        let target = Target::Composition(self.composition()?.clone());
+        let text = OsString::from(&self.session.preedit).to_wchars();
         log::trace!("Preedit wchar text: {:?}", text);
-        edit_session::set_text(
+        let caret = match self.session.caret {
+            Some(caret) => self
+                .session
+                .preedit
+                .chars()
+                .take(caret)
+                .map(char::len_utf16)
+                .sum(),
+            None => text.len(),
+        };
+        self.edits.set_text(
             self.tid,
             self.context()?,
-            range,
-            &text,
-            self.display_attribute.as_ref(),
+            target,
+            text,
+            caret,
+            self.preedit_attributes(),
         )
     }
 
-    fn update_candidate_list(&mut self) -> Result<()> {
+    /// A fixed layout has riti's conversion itself in the preedit. Phonetic input shows the
+    /// roman input instead, of which the part the selected suggestion converts is the target
+    /// and what the suggestion keeps as typed, e.g. trailing punctuation, stays input.
+    fn preedit_attributes(&self) -> Attributes {
+        let suggestion = self.session.suggestions.as_ref().unwrap();
+        let len = self.session.preedit.encode_utf16().count();
+        let segments = if suggestion.is_lonely() {
+            vec![(0..len, DisplayAttribute::Converted)]
+        } else if let Some(selected) = suggestion
+            .get_suggestions()
+            .get(suggestion.previously_selected_index())
+        {
+            let target = converted_span(&self.session.preedit, selected);
+            vec![
+                (0..target.start, DisplayAttribute::Input),
+                (target.clone(), DisplayAttribute::Target),
+                (target.end..len, DisplayAttribute::Input),
+            ]
+        } else {
+            vec![(0..len, DisplayAttribute::Input)]
+        };
+        segments
+            .into_iter()
+            .filter(|(range, _)| !range.is_empty())
+            .filter_map(|(range, attribute)| Some((range, self.display_attribute(attribute)?)))
+            .collect()
+    }
+
+    pub fn update_candidate_list(&mut self) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
         self.assure_candidate_list()?;
         let candidate_list = self.candidate_list()?;
-        if self.suggestions.as_ref().unwrap().is_empty() {
+        if self.session.candidates.is_empty() {
             candidate_list.hide();
         } else {
-            candidate_list.show(self.suggestions.as_ref().unwrap().get_suggestions())?;
+            candidate_list.show(&self.session.candidates)?;
             if let Some((x, y)) = self.get_pos() {
                 candidate_list.locate(x, y)?;
             }
@@ -81,19 +150,54 @@ impl TextServiceInner {
         Ok(())
     }
 
-    fn set_text(&self, text: &str) -> Result<()> {
+    /// Gather the candidates to show: riti's suggestions with exact emoji matches trailing
+    /// behind, or only emoji when the input starts with the trigger and anything matches.
+    fn collect_candidates(&mut self) {
+        let suggestions = self.session.suggestions.as_ref().unwrap().get_suggestions();
+        self.session.candidates.clear();
+        let hints = self.session.hints;
+        let suggestions = suggestions.iter().map(|sugg| hints.apply(sugg.clone()));
+        if !self.emoji_enabled {
+            self.session.candidates.extend(suggestions);
+            self.session.riti_candidates = self.session.candidates.len();
+            return;
+        }
+        let table = EmojiTable::get();
+        if !self.emoji_trigger.is_empty()
+            && let Some(keyword) = self.session.preedit.strip_prefix(&self.emoji_trigger)
+        {
+            let emoji = table.search(keyword, CANDI_NUM);
+            if !emoji.is_empty() {
+                self.session.riti_candidates = 0;
+                self.session.candidates = emoji;
+                return;
+            }
+        }
+        let emoji = table.exact(&self.session.preedit);
+        let emoji = &emoji[..emoji.len().min(EMOJI_TRAILING_NUM)];
+        self.session
+            .candidates
+            .extend(suggestions.take(CANDI_NUM - emoji.len()));
+        self.session.riti_candidates = self.session.candidates.len();
+        self.session.candidates.extend_from_slice(emoji);
+    }
+
+    pub fn set_text(&self, text: &str) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
         let text = OsString::from(text).to_wchars();
-        let range = unsafe { self.composition()?.GetRange()? };
-        edit_session::set_text(self.tid, self.context()?, range, &text, None)
+        let target = Target::Composition(self.composition()?.clone());
+        let len = text.len();
+        self.edits
+            .set_text(self.tid, self.context()?, target, text, len, Vec::new())
     }
 
     fn get_pos(&self) -> Option<(i32, i32)> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        let range = unsafe { self.composition().ok()?.GetRange().ok()? };
-        let pos = edit_session::get_pos(self.tid, self.context().ok()?, &range).ok()?;
+        let range = self.composition().ok()?.range()?;
+        let pos =
+            edit_session::get_pos(&self.edits, self.tid, self.context().ok()?, &range).ok()?;
         if pos.0 <= 0 && pos.1 <= 0 {
             debug!("Abnormal position: ({}, {})", pos.0, pos.1);
             None
@@ -102,10 +206,10 @@ impl TextServiceInner {
         }
     }
 
-    fn composition(&self) -> Result<&ITfComposition> {
+    fn composition(&self) -> Result<&Composition> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        self.composition.as_ref().ok_or(E_FAIL.into())
+        self.session.composition.as_ref().ok_or(E_FAIL.into())
     }
 }
 
@@ -114,6 +218,16 @@ impl TextServiceInner {
 impl TextServiceInner {
     pub fn keypress(&mut self, key: u16, modifier: u8) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+        // riti appends the key to the end, so it's typed again along with the input around it
+        if let Some(caret) = self.session.caret {
+            let roman: Vec<char> = self.session.preedit.chars().collect();
+            let (before, after) = roman.split_at(caret);
+            let mut keys = roman_keys(before);
+            keys.push((key, modifier));
+            keys.extend(roman_keys(after));
+            return self.retype(&keys, caret + 1);
+        }
+
         let mut selected: u8 = 0;
 
         if let Ok(candidate_list) = self.candidate_list() {
@@ -121,48 +235,126 @@ impl TextServiceInner {
         }
 
         let suggestion = self.riti.get_suggestion_for_key(key, modifier, selected);
+        self.apply_suggestion(suggestion)
+    }
 
-        if suggestion.is_lonely() {
-            self.preedit = suggestion.get_pre_edit_text(0);
-            self.update_preedit()?;
-            self.suggestions = Some(suggestion);
-        } else {
-            self.preedit = suggestion.get_auxiliary_text().to_string();
-            let prev = suggestion.previously_selected_index();
+    pub fn pop(&mut self, ctrl: bool) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+        log::info!("Backspace ctrl: {ctrl}");
+        if let Some(caret) = self.session.caret {
+            if caret == 0 {
+                return Ok(());
+            }
+            // Ctrl+Backspace removes everything before the caret
+            let from = if ctrl { 0 } else { caret.saturating_sub(1) };
+            let mut roman: Vec<char> = self.session.preedit.chars().collect();
+            roman.drain(from..caret);
+            return self.retype(&roman_keys(&roman), from);
+        }
 
-            self.suggestions = Some(suggestion);
-            self.update_preedit()?;
+        let suggestion = self.riti.backspace_event(ctrl);
 
-            self.update_candidate_list()?;
+        // reverting a selection happens after the composition ended, see undo_commit
+        if suggestion.is_empty() {
+            self.session.preedit.clear();
+            return self.abort();
+        }
 
-            if prev != 0 {
-                self.candidate_list()?.set_highlight(prev);
-            }
+        self.apply_suggestion(suggestion)
+    }
+
+    /// Remove the character after the caret.
+    pub fn delete(&mut self) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+        let Some(caret) = self.session.caret else {
+            return Ok(());
         };
+        let mut roman: Vec<char> = self.session.preedit.chars().collect();
+        roman.remove(caret);
+        self.retype(&roman_keys(&roman), caret)
+    }
+
+    /// Move the caret by `offset` characters within the roman input.
+    pub fn move_caret(&mut self, offset: isize) -> Result<()> {
+        let caret = self
+            .session
+            .caret
+            .unwrap_or(self.session.preedit.chars().count());
+        self.set_caret(caret.saturating_add_signed(offset))
+    }
 
+    /// Put the caret before the `caret`th character of the roman input, or at the end if
+    /// there are not that many.
+    pub fn set_caret(&mut self, caret: usize) -> Result<()> {
+        if !self.is_preedit_editable() {
+            return Ok(());
+        }
+        let caret = (caret < self.session.preedit.chars().count()).then_some(caret);
+        if caret != self.session.caret {
+            self.session.caret = caret;
+            self.update_preedit()?;
+        }
         Ok(())
     }
 
-    pub fn pop(&mut self, ctrl: bool) -> Result<()> {
-        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-        log::info!("Backspace ctrl: {ctrl}");
-        let suggestion = self.riti.backspace_event(ctrl);
+    /// Only roman input can be edited. riti shows the converted text itself when there's
+    /// no candidate window, which is not something to be typed again.
+    pub fn is_preedit_editable(&self) -> bool {
+        !self.session.suggestions.as_ref().unwrap().is_lonely() && self.session.preedit.is_ascii()
+    }
 
-        // todo pop can be used to revert selection
-        if suggestion.is_empty() {
-            self.preedit.clear();
+    /// Replace the roman input as a whole, given as keys and their modifiers. riti only takes
+    /// keys one by one so the input is typed into a fresh session from the beginning.
+    fn retype(&mut self, keys: &[(u16, u8)], caret: usize) -> Result<()> {
+        let Some(suggestion) = self.type_keys(keys) else {
+            self.session.preedit.clear();
             return self.abort();
+        };
+        self.session.caret = (caret < keys.len()).then_some(caret);
+        self.apply_suggestion(suggestion)
+    }
+
+    /// Type `roman` into a fresh input session of riti. None if riti has nothing for it.
+    pub fn type_roman(&mut self, roman: &[char]) -> Option<Suggestion> {
+        self.type_keys(&roman_keys(roman))
+    }
+
+    fn type_keys(&mut self, keys: &[(u16, u8)]) -> Option<Suggestion> {
+        self.riti.finish_input_session();
+        let mut suggestion = None;
+        for &(key, modifier) in keys {
+            suggestion = Some(self.riti.get_suggestion_for_key(key, modifier, 0));
         }
+        suggestion.filter(|suggestion| !suggestion.is_empty())
+    }
 
+    /// Show a suggestion from riti in the preedit and the candidate list.
+    pub fn apply_suggestion(&mut self, suggestion: Suggestion) -> Result<()> {
         if suggestion.is_lonely() {
-            self.preedit = suggestion.get_pre_edit_text(0);
-            self.suggestions = Some(suggestion);
+            self.session.preedit = suggestion.get_pre_edit_text(0);
+            self.session.suggestions = Some(suggestion);
+            self.session.candidates.clear();
             self.update_preedit()?;
         } else {
-            self.preedit = suggestion.get_auxiliary_text().to_string();
-            self.suggestions = Some(suggestion);
+            self.session.preedit = suggestion.get_auxiliary_text().to_string();
+            let prev = suggestion.previously_selected_index();
+
+            self.session.suggestions = Some(suggestion);
             self.update_preedit()?;
+
+            self.collect_candidates();
             self.update_candidate_list()?;
+
+            if prev != 0 && prev < self.session.riti_candidates {
+                self.candidate_list()?.set_highlight(prev);
+            } else if self.session.hints.after_hasanta
+                && let Some(index) = self.session.candidates[..self.session.riti_candidates]
+                    .iter()
+                    .position(|candidate| !candidate.is_ascii())
+            {
+                // English words can't go on with a conjunct
+                self.candidate_list()?.set_highlight(index);
+            }
         };
 
         Ok(())
@@ -174,7 +366,7 @@ impl TextServiceInner {
 
         let mut selected = 0;
 
-        if !self.suggestions.as_ref().unwrap().is_lonely()
+        if !self.session.suggestions.as_ref().unwrap().is_lonely()
             && let Ok(candidate_list) = self.candidate_list()
         {
             selected = candidate_list.get_highlighted_index();
@@ -187,10 +379,16 @@ impl TextServiceInner {
     pub fn force_commit(&mut self, ch: char) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        if self.suggestions.as_ref().unwrap().is_empty() {
+        if self.session.suggestions.as_ref().unwrap().is_empty() {
             self.force_release(ch)
         } else {
-            let sugg = self.suggestions.as_ref().unwrap().get_pre_edit_text(0);
+            let sugg = self
+                .session
+                .suggestions
+                .as_ref()
+                .unwrap()
+                .get_pre_edit_text(0);
+            let sugg = self.session.hints.apply(sugg);
 
             self.set_text(&sugg)?;
             self.end_composition()
@@ -201,15 +399,28 @@ impl TextServiceInner {
     pub fn select(&mut self, index: usize, append: Option<char>) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        if !self.suggestions.as_ref().unwrap().is_lonely()
-            && index >= self.suggestions.as_ref().unwrap().len()
-        {
+        let lonely = self.session.suggestions.as_ref().unwrap().is_lonely();
+        if !lonely && index >= self.session.candidates.len() {
             return Ok(());
         }
 
-        let sugg = self.suggestions.as_ref().unwrap().get_pre_edit_text(index);
-
-        self.riti.candidate_committed(index);
+        let sugg = if !lonely && index >= self.session.riti_candidates {
+            // picked from the emoji layer, riti has nothing to learn from it
+            self.session.candidates[index].clone()
+        } else {
+            self.riti.candidate_committed(index);
+            let sugg = self
+                .session
+                .suggestions
+                .as_ref()
+                .unwrap()
+                .get_pre_edit_text(index);
+            let sugg = self.session.hints.apply(sugg);
+            if self.is_preedit_editable() {
+                self.remember_roman(&sugg);
+            }
+            sugg
+        };
 
         let sugg = if let Some(c) = append {
             &format!("{}{}", sugg, c)
@@ -218,22 +429,83 @@ impl TextServiceInner {
         };
 
         self.set_text(&sugg)?;
+        // the range is unknown if the composition has yet to start
+        self.session.last_commit = match self.composition()?.range() {
+            Some(range) if self.is_preedit_editable() => Some(LastCommit {
+                text: sugg.clone(),
+                range,
+                roman: self.session.preedit.clone(),
+                selected: index,
+                appended: append,
+            }),
+            _ => None,
+        };
 
         self.end_composition()
     }
 
+    /// Take the last commit back into a composition, with the candidate it was picked from
+    /// highlighted. Returns false if there's no commit to undo or the text has changed since.
+    pub fn undo_commit(&mut self, context: &ITfContext) -> Result<bool> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        self.switch_context(context)?;
+        let Some(last) = self.session.last_commit.take() else {
+            return Ok(false);
+        };
+        if self.session.composition.is_some()
+            || !edit_session::is_caret_after(
+                &self.edits,
+                self.tid,
+                context,
+                &last.range,
+                &last.text,
+            )?
+        {
+            return Ok(false);
+        }
+        let roman: Vec<char> = last.roman.chars().collect();
+        let Some(suggestion) = self.type_roman(&roman) else {
+            self.riti.finish_input_session();
+            return Ok(false);
+        };
+        trace!("Undoing the commit of {}.", last.text);
+        // what followed the candidate stays in the document after the composition
+        let range = match last.appended {
+            Some(ch) => {
+                edit_session::trim_end(&self.edits, self.tid, context, &last.range, ch.len_utf16())?
+            }
+            None => last.range,
+        };
+        self.start_composition(Some(&range))?;
+        self.apply_suggestion(suggestion)?;
+        if last.selected < self.session.candidates.len() {
+            self.candidate_list()?.set_highlight(last.selected);
+        }
+        Ok(true)
+    }
+
+    /// Whether Backspace would undo the last commit rather than delete a character of it.
+    /// Commits followed by a space or a new line are not, Backspace deletes those as usual.
+    pub fn can_undo_by_backspace(&self) -> bool {
+        self.session
+            .last_commit
+            .as_ref()
+            .is_some_and(|last| last.appended.is_none())
+    }
+
     // Release the raw ascii chars
     pub fn release(&mut self) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        self.set_text(&self.preedit)?;
+        self.set_text(&self.session.preedit)?;
         self.end_composition()
     }
 
     fn force_release(&mut self, ch: char) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-        self.preedit.push(ch);
-        self.set_text(&self.preedit)?;
+        self.session.preedit.push(ch);
+        self.set_text(&self.session.preedit)?;
         self.end_composition()
     }
 
@@ -241,12 +513,42 @@ impl TextServiceInner {
     pub fn abort(&mut self) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        let _ = self.set_text(&self.preedit);
+        let _ = self.cancel_preview();
+        self.session.last_commit = None;
+        let _ = self.set_text(&self.session.preedit);
+
+        self.end_composition()
+    }
+
+    // Ended by the application. Whatever is still queued for the composition has nowhere
+    // to go, and the document is left as the application made it.
+    fn composition_terminated(&mut self) -> Result<()> {
+        if let Some(composition) = self.session.composition.take() {
+            composition.terminate();
+            self.edits.discard(&composition);
+        }
+        let _ = self.cancel_preview();
+        self.session.last_commit = None;
+        self.end_composition()
+    }
+
+    // Cancelled by the user. Nothing is left behind.
+    pub fn cancel(&mut self) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
+        self.session.last_commit = None;
+        self.set_text("")?;
         self.end_composition()
     }
 }
 
+/// The keys typing `roman`, without modifiers.
+fn roman_keys(roman: &[char]) -> Vec<(u16, u8)> {
+    type_text(roman.iter().copied())
+        .map(|key| (key.keycode, 0))
+        .collect()
+}
+
 #[allow(non_snake_case)]
 impl ITfCompositionSink_Impl for TextService {
     fn OnCompositionTerminated(
@@ -262,6 +564,53 @@ impl ITfCompositionSink_Impl for TextService {
         // because `pop` waits for the completion of this method
         // and this method waits for the releasing of the lock held by `pop`.
         // `self.try_lock()` avoids such issue
-        self.try_write()?.abort()
+        let mut inner = self.try_write()?;
+        inner.composition_terminated()
+    }
+}
+
+/// The part of `roman` that riti converted into `converted`, in UTF-16 units: all of it but
+/// the characters the conversion kept as typed at either end. A suggestion that is the input
+/// itself, e.g. an English word, takes all of it.
+fn converted_span(roman: &str, converted: &str) -> Range<usize> {
+    let units = |chars: &[char]| chars.iter().map(|ch| ch.len_utf16()).sum::<usize>();
+    let roman: Vec<char> = roman.chars().collect();
+    let converted: Vec<char> = converted.chars().collect();
+    if roman == converted {
+        return 0..units(&roman);
+    }
+    let start = roman
+        .iter()
+        .zip(&converted)
+        .take_while(|(typed, kept)| typed == kept)
+        .count();
+    let kept = roman[start..]
+        .iter()
+        .rev()
+        .zip(converted[start..].iter().rev())
+        .take_while(|(typed, kept)| typed == kept)
+        .count();
+    units(&roman[..start])..units(&roman[..roman.len() - kept])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converted_whole() {
+        assert_eq!(converted_span("ami", "আমি"), 0..3);
+        assert_eq!(converted_span("k", "ক"), 0..1);
+    }
+
+    #[test]
+    fn kept_punctuation() {
+        assert_eq!(converted_span("ami,", "আমি,"), 0..3);
+        assert_eq!(converted_span("(ami)", "(আমি)"), 1..4);
+    }
+
+    #[test]
+    fn english() {
+        assert_eq!(converted_span("hello", "hello"), 0..5);
     }
 }
@@ -1,19 +1,21 @@
-use std::sync::atomic::{AtomicBool, Ordering::*};
+use std::cell::Cell;
 
 use windows::{
     Win32::{
-        Foundation::{E_INVALIDARG, E_NOTIMPL},
+        Foundation::{E_INVALIDARG, E_NOTIMPL, S_FALSE},
+        Graphics::Gdi::{COLOR_HIGHLIGHT, COLOR_HIGHLIGHTTEXT},
         UI::TextServices::{
             IEnumTfDisplayAttributeInfo, IEnumTfDisplayAttributeInfo_Impl, ITfDisplayAttributeInfo,
-            ITfDisplayAttributeInfo_Impl, ITfDisplayAttributeProvider_Impl, TF_ATTR_INPUT,
-            TF_DA_COLOR, TF_DISPLAYATTRIBUTE, TF_LS_SOLID,
+            ITfDisplayAttributeInfo_Impl, ITfDisplayAttributeProvider_Impl, TF_ATTR_CONVERTED,
+            TF_ATTR_INPUT, TF_ATTR_TARGET_CONVERTED, TF_CT_SYSCOLOR, TF_DA_COLOR, TF_DA_COLOR_0,
+            TF_DISPLAYATTRIBUTE, TF_LS_DOT, TF_LS_SOLID,
         },
     },
-    core::{BSTR, GUID, Result, implement},
+    core::{BSTR, GUID, Result, VARIANT, implement},
 };
 
-use super::TextService;
-use crate::{DISPLAY_ATTR_ID, global};
+use super::{TextService, TextServiceInner};
+use crate::global::{DISPLAY_ATTR_CONVERTED_ID, DISPLAY_ATTR_ID, DISPLAY_ATTR_TARGET_ID};
 
 //---------------------------------------------------------------------------------
 //
@@ -29,36 +31,120 @@ impl ITfDisplayAttributeProvider_Impl for TextService {
     fn EnumDisplayAttributeInfo(&self) -> Result<IEnumTfDisplayAttributeInfo> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        Ok(EnumDisplayAttributeInfo::create())
+        let highlight_target = self.write()?.highlight_target;
+        Ok(EnumDisplayAttributeInfo::create(highlight_target))
     }
     fn GetDisplayAttributeInfo(&self, guid: *const GUID) -> Result<ITfDisplayAttributeInfo> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        if unsafe { *guid == global::DISPLAY_ATTR_ID } {
-            Ok(DisplayAttributeInfo::create())
+        let guid = unsafe { guid.as_ref() }.ok_or(E_INVALIDARG)?;
+        let attribute = DisplayAttribute::ALL
+            .into_iter()
+            .find(|attribute| attribute.guid() == *guid)
+            .ok_or(E_INVALIDARG)?;
+        let highlight_target = self.write()?.highlight_target;
+        Ok(DisplayAttributeInfo::create(attribute, highlight_target))
+    }
+}
+
+impl TextServiceInner {
+    /// The atom of `attribute` to set as GUID_PROP_ATTRIBUTE, None if it's not registered.
+    pub fn display_attribute(&self, attribute: DisplayAttribute) -> Option<VARIANT> {
+        self.display_attributes
+            .iter()
+            .find(|(registered, _)| *registered == attribute)
+            .map(|(_, atom)| atom.clone())
+    }
+}
+
+//----------------------------------------------------------------------------
+//
+//  The display attributes of the preedit. Roman input is dotted, text riti
+//  has converted already is underlined, and underlined thick (optionally
+//  highlighted) while the candidates on screen are for it.
+//
+//----------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayAttribute {
+    Input,
+    Converted,
+    Target,
+}
+
+impl DisplayAttribute {
+    pub const ALL: [DisplayAttribute; 3] = [
+        DisplayAttribute::Input,
+        DisplayAttribute::Converted,
+        DisplayAttribute::Target,
+    ];
+
+    pub fn guid(self) -> GUID {
+        match self {
+            DisplayAttribute::Input => DISPLAY_ATTR_ID,
+            DisplayAttribute::Converted => DISPLAY_ATTR_CONVERTED_ID,
+            DisplayAttribute::Target => DISPLAY_ATTR_TARGET_ID,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            DisplayAttribute::Input => "Input",
+            DisplayAttribute::Converted => "Converted",
+            DisplayAttribute::Target => "Target",
+        }
+    }
+
+    fn info(self, highlight_target: bool) -> TF_DISPLAYATTRIBUTE {
+        let (line_style, bold_line, info) = match self {
+            DisplayAttribute::Input => (TF_LS_DOT, false, TF_ATTR_INPUT),
+            DisplayAttribute::Converted => (TF_LS_SOLID, false, TF_ATTR_CONVERTED),
+            DisplayAttribute::Target => (TF_LS_SOLID, true, TF_ATTR_TARGET_CONVERTED),
+        };
+        let (text, background) = if self == DisplayAttribute::Target && highlight_target {
+            (
+                system_color(COLOR_HIGHLIGHTTEXT.0),
+                system_color(COLOR_HIGHLIGHT.0),
+            )
         } else {
-            Err(E_INVALIDARG.into())
+            (TF_DA_COLOR::default(), TF_DA_COLOR::default())
+        };
+        TF_DISPLAYATTRIBUTE {
+            crText: text,
+            crBk: background,
+            crLine: TF_DA_COLOR::default(),
+            lsStyle: line_style,
+            fBoldLine: bold_line.into(),
+            bAttr: info,
         }
     }
 }
 
+fn system_color(index: i32) -> TF_DA_COLOR {
+    TF_DA_COLOR {
+        r#type: TF_CT_SYSCOLOR,
+        Anonymous: TF_DA_COLOR_0 { nIndex: index },
+    }
+}
+
 //----------------------------------------------------------------------------
 //
 //  An enumerator that enumerates through all possible display atrributes.
-//  The input method has only one display attribute so this is kinda dumb.
 //
 //----------------------------------------------------------------------------
 
 #[implement(IEnumTfDisplayAttributeInfo)]
 struct EnumDisplayAttributeInfo {
-    enumerated: AtomicBool,
+    index: Cell<usize>,
+    highlight_target: bool,
 }
 impl EnumDisplayAttributeInfo {
-    fn create() -> IEnumTfDisplayAttributeInfo {
+    fn create(highlight_target: bool) -> IEnumTfDisplayAttributeInfo {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
         IEnumTfDisplayAttributeInfo::from(Self {
-            enumerated: AtomicBool::new(false),
+            index: Cell::new(0),
+            highlight_target,
         })
     }
 }
@@ -68,25 +154,34 @@ impl IEnumTfDisplayAttributeInfo_Impl for EnumDisplayAttributeInfo {
     fn Clone(&self) -> Result<IEnumTfDisplayAttributeInfo> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        Err(E_NOTIMPL.into())
+        Ok(IEnumTfDisplayAttributeInfo::from(Self {
+            index: self.index.clone(),
+            highlight_target: self.highlight_target,
+        }))
     }
 
     fn Next(
         &self,
-        _count: u32,
+        count: u32,
         info: *mut Option<ITfDisplayAttributeInfo>,
         fetched: *mut u32,
     ) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
+        let start = self.index.get();
+        let end = DisplayAttribute::ALL.len().min(start + count as usize);
         // Dear MS please fix these raw pointers thanks
-        unsafe {
-            if self.enumerated.fetch_and(true, Relaxed) {
-                *info = Some(DisplayAttributeInfo::create());
-                *fetched = 1;
-            } else {
-                *fetched = 0;
-            }
+        for (offset, attribute) in DisplayAttribute::ALL[start..end].iter().enumerate() {
+            let created = DisplayAttributeInfo::create(*attribute, self.highlight_target);
+            unsafe { *info.add(offset) = Some(created) };
+        }
+        self.index.set(end);
+        if !fetched.is_null() {
+            unsafe { *fetched = (end - start) as u32 };
+        }
+        // running out early is how the end of the list is told
+        if end - start < count as usize {
+            return Err(S_FALSE.into());
         }
         Ok(())
     }
@@ -94,34 +189,40 @@ impl IEnumTfDisplayAttributeInfo_Impl for EnumDisplayAttributeInfo {
     fn Reset(&self) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        self.enumerated.fetch_and(false, Relaxed);
+        self.index.set(0);
         Ok(())
     }
 
     fn Skip(&self, count: u32) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        if count > 0 {
-            self.enumerated.fetch_and(true, Relaxed);
-        }
+        let index = DisplayAttribute::ALL
+            .len()
+            .min(self.index.get() + count as usize);
+        self.index.set(index);
         Ok(())
     }
 }
 
 //----------------------------------------------------------------------------
 //
-//  Our one and only display attribute that does nothing but adding underlines
+//  A display attribute, handed out to the applications that draw them.
 //
 //----------------------------------------------------------------------------
 
 #[implement(ITfDisplayAttributeInfo)]
-#[derive(Default)]
-pub struct DisplayAttributeInfo;
+pub struct DisplayAttributeInfo {
+    attribute: DisplayAttribute,
+    highlight_target: bool,
+}
 impl DisplayAttributeInfo {
-    pub fn create() -> ITfDisplayAttributeInfo {
+    pub fn create(attribute: DisplayAttribute, highlight_target: bool) -> ITfDisplayAttributeInfo {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        ITfDisplayAttributeInfo::from(Self {})
+        ITfDisplayAttributeInfo::from(Self {
+            attribute,
+            highlight_target,
+        })
     }
 }
 
@@ -130,28 +231,19 @@ impl ITfDisplayAttributeInfo_Impl for DisplayAttributeInfo {
     fn GetGUID(&self) -> Result<GUID> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        Ok(DISPLAY_ATTR_ID)
+        Ok(self.attribute.guid())
     }
 
     fn GetDescription(&self) -> Result<BSTR> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        Err(E_INVALIDARG.into())
+        Ok(BSTR::from(self.attribute.description()))
     }
 
     fn GetAttributeInfo(&self, attr: *mut TF_DISPLAYATTRIBUTE) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        unsafe {
-            *attr = TF_DISPLAYATTRIBUTE {
-                crText: TF_DA_COLOR::default(),
-                crBk: TF_DA_COLOR::default(),
-                crLine: TF_DA_COLOR::default(),
-                lsStyle: TF_LS_SOLID,
-                fBoldLine: false.into(),
-                bAttr: TF_ATTR_INPUT,
-            };
-        }
+        unsafe { *attr = self.attribute.info(self.highlight_target) };
         Ok(())
     }
 
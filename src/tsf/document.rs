@@ -0,0 +1,31 @@
+use log::trace;
+use windows::Win32::UI::TextServices::ITfContext;
+
+use super::{TextServiceInner, edit_queue::EditQueue, edit_session};
+use crate::surrounding::{Document, Hints};
+
+/// A TSF context seen as a document, with the caret at the start of the selection.
+pub struct ContextDocument<'a> {
+    edits: &'a EditQueue,
+    tid: u32,
+    context: &'a ITfContext,
+}
+
+impl Document for ContextDocument<'_> {
+    fn text_before_caret(&self, max: usize) -> Option<String> {
+        edit_session::get_text_before(self.edits, self.tid, self.context, max).ok()
+    }
+}
+
+impl TextServiceInner {
+    /// Look at the text before the caret of `context` before a new word is typed.
+    pub fn read_hints(&mut self, context: &ITfContext) {
+        let document = ContextDocument {
+            edits: &self.edits,
+            tid: self.tid,
+            context,
+        };
+        self.session.hints = Hints::from_document(&document);
+        trace!("{:?}", self.session.hints);
+    }
+}
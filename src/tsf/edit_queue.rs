@@ -0,0 +1,359 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    mem::{self, ManuallyDrop},
+    ops::Range,
+    ptr,
+    rc::Rc,
+};
+
+use log::{debug, error, trace};
+use windows::{
+    Win32::{
+        Foundation::{E_FAIL, FALSE, S_OK},
+        UI::TextServices::{
+            GUID_PROP_ATTRIBUTE, ITfComposition, ITfCompositionSink, ITfContext,
+            ITfContextComposition, ITfEditSession, ITfEditSession_Impl, ITfInsertAtSelection,
+            ITfRange, TF_AE_NONE, TF_ANCHOR_END, TF_ANCHOR_START, TF_ES_READWRITE, TF_ES_SYNC,
+            TF_IAS_QUERYONLY, TF_S_ASYNC, TF_SELECTION, TF_SELECTIONSTYLE, TF_ST_CORRECTION,
+        },
+    },
+    core::{Interface, Result, VARIANT, implement},
+};
+
+//----------------------------------------------------------------------------
+//
+//  Edits to the document. Applications don't always grant an edit session
+//  right away: they may answer TF_S_ASYNC and run it later, once they are
+//  done with the document. Edits are therefore queued and run in the order
+//  they were made, either right away or when the session is finally granted.
+//
+//  A composition started by a pending edit doesn't exist until the edit runs
+//  so it's handed out as a slot, filled in when the composition does start.
+//  Edits made to the composition in the meantime find it there.
+//
+//----------------------------------------------------------------------------
+
+/// A composition that may not have been started yet.
+#[derive(Clone, Default)]
+pub struct Composition(Rc<RefCell<Option<ITfComposition>>>);
+
+impl Composition {
+    pub fn get(&self) -> Option<ITfComposition> {
+        self.0.borrow().clone()
+    }
+
+    /// The range the composition covers, None if it has not started yet.
+    pub fn range(&self) -> Option<ITfRange> {
+        unsafe { self.get()?.GetRange().ok() }
+    }
+
+    /// Forget a composition the application terminated.
+    pub fn terminate(&self) {
+        self.0.borrow_mut().take();
+    }
+
+    fn is(&self, other: &Composition) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// Display attributes of parts of a text, counted in UTF-16 units from its start.
+pub type Attributes = Vec<(Range<usize>, VARIANT)>;
+
+/// Where a text goes.
+pub enum Target {
+    /// Over the composition, wherever it ends up being.
+    Composition(Composition),
+    Range(ITfRange),
+}
+
+enum Edit {
+    StartComposition {
+        composition: Composition,
+        range: Option<ITfRange>,
+        sink: ITfCompositionSink,
+    },
+    SetText {
+        target: Target,
+        text: Vec<u16>,
+        caret: usize,
+        attributes: Attributes,
+    },
+    EndComposition(Composition),
+}
+
+/// Edits waiting for a session on `context`.
+#[derive(Default)]
+struct Queue {
+    context: Option<ITfContext>,
+    edits: VecDeque<Edit>,
+    // a session was requested and has not run yet
+    pending: bool,
+}
+
+/// The edits made by a text service, shared with the session that runs them.
+#[derive(Default)]
+pub struct EditQueue(RefCell<Rc<RefCell<Queue>>>);
+
+impl EditQueue {
+    /// Start a composition at the selection, or over `range` if given.
+    pub fn start_composition(
+        &self,
+        tid: u32,
+        context: &ITfContext,
+        range: Option<ITfRange>,
+        sink: ITfCompositionSink,
+    ) -> Result<Composition> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        trace!("start_composition");
+        let composition = Composition::default();
+        let edit = Edit::StartComposition {
+            composition: composition.clone(),
+            range,
+            sink,
+        };
+        // run already but nothing came out of it
+        if !self.push(tid, context, edit)? && composition.get().is_none() {
+            return Err(E_FAIL.into());
+        }
+        Ok(composition)
+    }
+
+    pub fn end_composition(
+        &self,
+        tid: u32,
+        context: &ITfContext,
+        composition: &Composition,
+    ) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        trace!("end_composition");
+        self.push(tid, context, Edit::EndComposition(composition.clone()))
+            .map(|_| ())
+    }
+
+    /// Replace the text of `target` and put the cursor at `caret`, counted in UTF-16 units
+    /// from the start of `text`. Any `caret` beyond the end puts the cursor at the end.
+    /// Text without any display attribute is committed text.
+    pub fn set_text(
+        &self,
+        tid: u32,
+        context: &ITfContext,
+        target: Target,
+        text: Vec<u16>,
+        caret: usize,
+        attributes: Attributes,
+    ) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        let edit = Edit::SetText {
+            target,
+            text,
+            caret,
+            attributes,
+        };
+        self.push(tid, context, edit).map(|_| ())
+    }
+
+    /// Drop the edits still waiting for `composition`, which the application terminated.
+    pub fn discard(&self, composition: &Composition) {
+        let queue = self.0.borrow();
+        queue.borrow_mut().edits.retain(|edit| match edit {
+            Edit::SetText {
+                target: Target::Composition(target),
+                ..
+            } => !target.is(composition),
+            Edit::EndComposition(ended) => !ended.is(composition),
+            _ => true,
+        });
+    }
+
+    /// Give up on the session requested for `context` if it's still not granted, along with
+    /// the edits waiting for it. Applications may never grant it, which would leave every
+    /// later edit waiting behind it.
+    pub fn drop_pending(&self, context: &ITfContext) {
+        let mut queue = self.0.borrow_mut();
+        if queue.borrow().pending && queue.borrow().context.as_ref() == Some(context) {
+            debug!("Dropping the edits waiting for a session.");
+            queue.borrow_mut().edits.clear();
+            *queue = Rc::default();
+        }
+    }
+
+    /// Run the edits still waiting for `context` right away, so that it can be read as it
+    /// will be. Fails if the application doesn't allow it.
+    pub fn flush(&self, tid: u32, context: &ITfContext) -> Result<()> {
+        let queue = self.0.borrow().clone();
+        if queue.borrow().edits.is_empty() || queue.borrow().context.as_ref() != Some(context) {
+            return Ok(());
+        }
+        trace!("Flushing the queued edits.");
+        let session = ITfEditSession::from(Session(queue));
+        let result =
+            unsafe { context.RequestEditSession(tid, &session, TF_ES_READWRITE | TF_ES_SYNC)? };
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Queue an edit and have it run as soon as the application allows.
+    /// Returns whether it's still waiting.
+    fn push(&self, tid: u32, context: &ITfContext, edit: Edit) -> Result<bool> {
+        {
+            let mut queue = self.0.borrow_mut();
+            // edits for another context can't wait in the same session,
+            // the pending one keeps the edits it already has
+            if queue.borrow().pending && queue.borrow().context.as_ref() != Some(context) {
+                *queue = Rc::default();
+            }
+            let mut queue = queue.borrow_mut();
+            queue.context = Some(context.clone());
+            queue.edits.push_back(edit);
+            if queue.pending {
+                trace!("Queued behind a pending edit session.");
+                return Ok(true);
+            }
+        }
+
+        let queue = self.0.borrow().clone();
+        let session = ITfEditSession::from(Session(queue.clone()));
+        let result = unsafe { context.RequestEditSession(tid, &session, TF_ES_READWRITE) }
+            .unwrap_or_else(|e| e.code());
+        if result == TF_S_ASYNC {
+            debug!("Edit session granted asynchronously.");
+            queue.borrow_mut().pending = true;
+            Ok(true)
+        } else if result != S_OK {
+            queue.borrow_mut().edits.clear();
+            Err(result.into())
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[implement(ITfEditSession)]
+struct Session(Rc<RefCell<Queue>>);
+
+impl ITfEditSession_Impl for Session {
+    #[allow(non_snake_case)]
+    fn DoEditSession(&self, ec: u32) -> Result<()> {
+        let Some(context) = self.0.borrow().context.clone() else {
+            return Ok(());
+        };
+        // edits may queue more edits while they run, those are run along
+        loop {
+            let Some(edit) = self.0.borrow_mut().edits.pop_front() else {
+                break;
+            };
+            if let Err(e) = run(ec, &context, edit) {
+                error!("Failed to edit. {}", e);
+            }
+        }
+        self.0.borrow_mut().pending = false;
+        Ok(())
+    }
+}
+
+fn run(ec: u32, context: &ITfContext, edit: Edit) -> Result<()> {
+    unsafe {
+        match edit {
+            Edit::StartComposition {
+                composition,
+                range,
+                sink,
+            } => {
+                // to get the current range (namely the selected text or simply the cursor) you insert "nothing"
+                // which genius came up with these APIs?
+                let range = match range {
+                    Some(range) => range,
+                    None => context
+                        .cast::<ITfInsertAtSelection>()?
+                        .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?,
+                };
+                let context_composition = context.cast::<ITfContextComposition>()?;
+                let started = context_composition.StartComposition(ec, &range, &sink)?;
+                *composition.0.borrow_mut() = Some(started);
+                Ok(())
+            }
+            Edit::SetText {
+                target,
+                text,
+                caret,
+                attributes,
+            } => {
+                let range = match target {
+                    Target::Composition(composition) => composition.range().ok_or_else(|| {
+                        debug!("Composition is gone. Dropping the text.");
+                        E_FAIL
+                    })?,
+                    Target::Range(range) => range,
+                };
+                set_text(ec, context, &range, &text, caret, &attributes)
+            }
+            Edit::EndComposition(composition) => {
+                let Some(ended) = mem::take(&mut *composition.0.borrow_mut()) else {
+                    return Ok(());
+                };
+                ended.EndComposition(ec)
+            }
+        }
+    }
+}
+
+fn set_text(
+    ec: u32,
+    context: &ITfContext,
+    range: &ITfRange,
+    text: &[u16],
+    caret: usize,
+    attributes: &[(Range<usize>, VARIANT)],
+) -> Result<()> {
+    unsafe {
+        // apply underscore
+        if !attributes.is_empty() {
+            range.SetText(ec, TF_ST_CORRECTION, text)?;
+            let prop = context.GetProperty(&GUID_PROP_ATTRIBUTE)?;
+            for (part, attribute) in attributes {
+                let part_range = range.Clone()?;
+                part_range.Collapse(ec, TF_ANCHOR_START)?;
+                let mut shifted = 0;
+                part_range.ShiftEnd(ec, part.end as i32, &mut shifted, ptr::null())?;
+                part_range.ShiftStart(ec, part.start as i32, &mut shifted, ptr::null())?;
+                if let Err(e) = prop.SetValue(ec, &part_range, attribute) {
+                    error!("Failed to set display attribute. {}", e);
+                }
+            }
+        } else {
+            // using 0 for dwflag will remove the propety
+            range.SetText(ec, 0, text)?;
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+        // move the cursor to the caret, which is the end most of the time
+        let range = range.Clone()?;
+        if caret < text.len() {
+            range.Collapse(ec, TF_ANCHOR_START)?;
+            let mut shifted = 0;
+            range.ShiftEnd(ec, caret as i32, &mut shifted, ptr::null())?;
+        }
+        range.Collapse(ec, TF_ANCHOR_END)?;
+        set_selection(ec, context, &range)
+    }
+}
+
+fn set_selection(ec: u32, context: &ITfContext, range: &ITfRange) -> Result<()> {
+    let selection = TF_SELECTION {
+        range: ManuallyDrop::new(Some(range.clone())),
+        style: TF_SELECTIONSTYLE {
+            ase: TF_AE_NONE,
+            fInterimChar: FALSE,
+        },
+    };
+    unsafe { context.SetSelection(ec, &[selection]) }
+}
@@ -1,173 +1,223 @@
-use std::{cell::Cell, mem::ManuallyDrop};
+use std::{cell::Cell, ffi::OsString, ptr, slice};
 
-use log::{error, trace};
 use windows::{
     Win32::{
-        Foundation::{BOOL, FALSE, RECT, S_OK},
+        Foundation::{BOOL, E_FAIL, RECT, S_OK},
+        System::Com::CoTaskMemFree,
         UI::TextServices::{
-            GUID_PROP_ATTRIBUTE, ITfComposition, ITfCompositionSink, ITfContext,
-            ITfContextComposition, ITfEditSession, ITfEditSession_Impl, ITfInsertAtSelection,
-            ITfRange, TF_AE_NONE, TF_ANCHOR_END, TF_ES_READWRITE, TF_IAS_QUERYONLY, TF_SELECTION,
-            TF_SELECTIONSTYLE, TF_ST_CORRECTION,
+            GUID_PROP_INPUTSCOPE, ITfContext, ITfEditSession, ITfEditSession_Impl, ITfInputScope,
+            ITfInsertAtSelection, ITfRange, InputScope, TF_ANCHOR_END, TF_ANCHOR_START, TF_ES_READ,
+            TF_ES_SYNC, TF_IAS_QUERYONLY, TF_TF_MOVESTART,
         },
     },
-    core::{AsImpl, Interface, Result, VARIANT, implement},
+    core::{AsImpl, IUnknown, Interface, Result, implement},
 };
 
+use super::edit_queue::EditQueue;
+use crate::{extend::OsStrExt2, romanize};
+
 //----------------------------------------------------------------------------
 //
 //  Edit of any kind must be operated in edit sessions.
 //  It's for safety reasons I guess.
 //  But it's a pain in the ass to use such sessions so let's hide them under functions.
+//  The ones here only read and do so synchronously, once the edits queued
+//  before them have run. Writes go through the edit queue, see
+//  tsf/edit_queue.rs.
 //
 //----------------------------------------------------------------------------
 
-pub fn start_composition(
+pub fn get_pos(
+    edits: &EditQueue,
     tid: u32,
     context: &ITfContext,
-    composition_sink: &ITfCompositionSink,
-) -> Result<ITfComposition> {
+    range: &ITfRange,
+) -> Result<(i32, i32)> {
     //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-    trace!("start_composition");
     #[implement(ITfEditSession)]
     struct Session<'a> {
         context: &'a ITfContext,
-        composition_sink: &'a ITfCompositionSink,
-        composition: Cell<Option<ITfComposition>>, // out
+        range: &'a ITfRange,
+        pos: Cell<(i32, i32)>,
     }
 
     impl ITfEditSession_Impl for Session<'_> {
         #[allow(non_snake_case)]
         fn DoEditSession(&self, ec: u32) -> Result<()> {
-            // to get the current range (namely the selected text or simply the cursor) you insert "nothing"
-            // which genius came up with these APIs?
-            let range = unsafe {
-                self.context
-                    .cast::<ITfInsertAtSelection>()?
-                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?
-            };
-            let context_composition = self.context.cast::<ITfContextComposition>()?;
-            let composition =
-                unsafe { context_composition.StartComposition(ec, &range, self.composition_sink)? };
-            self.composition.set(Some(composition));
-            Ok(())
+            unsafe {
+                let mut rect = RECT::default();
+                let mut clipped = BOOL::default();
+                let view = self.context.GetActiveView()?;
+                view.GetTextExt(ec, self.range, &mut rect, &mut clipped)?;
+                self.pos.set((rect.left, rect.bottom));
+                Ok(())
+            }
         }
     }
 
+    edits.flush(tid, context)?;
     let session = ITfEditSession::from(Session {
         context,
-        composition_sink,
-        composition: Cell::new(None),
+        range,
+        pos: Cell::new((0, 0)),
     });
-
     unsafe {
-        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        let result = context.RequestEditSession(tid, &session, TF_ES_READ | TF_ES_SYNC)?;
         if result != S_OK {
             Err(result.into())
         } else {
             let session: &Session = session.as_impl();
-            Ok(session.composition.take().expect("Composition is None."))
+            Ok(session.pos.take())
         }
     }
 }
 
-pub fn end_composition(tid: u32, context: &ITfContext, composition: &ITfComposition) -> Result<()> {
-    //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-
-    trace!("end_composition");
+/// Input scopes (password, URL, number...) declared by the application for the text at the selection.
+pub fn get_input_scopes(
+    edits: &EditQueue,
+    tid: u32,
+    context: &ITfContext,
+) -> Result<Vec<InputScope>> {
     #[implement(ITfEditSession)]
-    struct Session<'a>(&'a ITfComposition);
+    struct Session<'a> {
+        context: &'a ITfContext,
+        scopes: Cell<Vec<InputScope>>,
+    }
+
     impl ITfEditSession_Impl for Session<'_> {
         #[allow(non_snake_case)]
         fn DoEditSession(&self, ec: u32) -> Result<()> {
-            unsafe { self.0.EndComposition(ec) }
+            unsafe {
+                let range = self
+                    .context
+                    .cast::<ITfInsertAtSelection>()?
+                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
+                let prop = self.context.GetProperty(&GUID_PROP_INPUTSCOPE)?;
+                // an empty value means the application declared nothing
+                let Ok(unknown) = IUnknown::try_from(&prop.GetValue(ec, &range)?) else {
+                    return Ok(());
+                };
+                let mut scopes = ptr::null_mut();
+                let mut count = 0;
+                unknown
+                    .cast::<ITfInputScope>()?
+                    .GetInputScopes(&mut scopes, &mut count)?;
+                if !scopes.is_null() {
+                    self.scopes
+                        .set(slice::from_raw_parts(scopes, count as usize).to_vec());
+                    CoTaskMemFree(Some(scopes as *const _));
+                }
+                Ok(())
+            }
         }
     }
-    let session = ITfEditSession::from(Session(composition));
+
+    edits.flush(tid, context)?;
+    let session = ITfEditSession::from(Session {
+        context,
+        scopes: Cell::new(Vec::new()),
+    });
     unsafe {
-        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        let result = context.RequestEditSession(tid, &session, TF_ES_READ | TF_ES_SYNC)?;
         if result != S_OK {
             Err(result.into())
         } else {
-            Ok(())
+            let session: &Session = session.as_impl();
+            Ok(session.scopes.take())
         }
     }
 }
 
-pub fn set_text(
+/// The committed Bangla word to reconvert: the text of `range` (or the selection if None)
+/// when it's a Bangla word, or the Bangla word around it when it's empty.
+pub fn get_word_range(
+    edits: &EditQueue,
     tid: u32,
     context: &ITfContext,
-    range: ITfRange,
-    text: &[u16],
-    dispaly_attribute: Option<&VARIANT>,
-) -> Result<()> {
-    //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-
+    range: Option<&ITfRange>,
+) -> Result<Option<(ITfRange, String)>> {
     #[implement(ITfEditSession)]
     struct Session<'a> {
         context: &'a ITfContext,
-        range: ITfRange,
-        text: &'a [u16],
-        dispaly_attribute: Option<&'a VARIANT>,
+        range: Option<&'a ITfRange>,
+        word: Cell<Option<(ITfRange, String)>>, // out
     }
 
     impl ITfEditSession_Impl for Session<'_> {
         #[allow(non_snake_case)]
         fn DoEditSession(&self, ec: u32) -> Result<()> {
             unsafe {
-                // apply underscore
-                if let Some(display_attribute) = self.dispaly_attribute {
-                    self.range.SetText(ec, TF_ST_CORRECTION, self.text)?;
-                    let prop = self.context.GetProperty(&GUID_PROP_ATTRIBUTE)?;
-                    if let Err(e) = prop.SetValue(ec, &self.range, display_attribute) {
-                        error!("Failed to set display attribute. {}", e);
+                let range = match self.range {
+                    Some(range) => range.Clone()?,
+                    None => self
+                        .context
+                        .cast::<ITfInsertAtSelection>()?
+                        .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?,
+                };
+                let mut shifted = 0;
+                if !range.IsEmpty(ec)?.as_bool() {
+                    let text = get_text(ec, &range)?;
+                    if text.len() <= MAX_WORD_LEN && text.iter().all(|&ch| is_bangla_unit(ch)) {
+                        self.word
+                            .set(Some((range, String::from_utf16_lossy(&text))));
                     }
-                } else {
-                    // using 0 for dwflag will remove the propety
-                    self.range.SetText(ec, 0, self.text)?;
+                    return Ok(());
                 }
-                if self.text.is_empty() {
+                // look around the caret for the word it's in
+                let before = range.Clone()?;
+                before.ShiftStart(ec, -(MAX_WORD_LEN as i32), &mut shifted, ptr::null())?;
+                let before = get_text(ec, &before)?;
+                let after = range.Clone()?;
+                after.ShiftEnd(ec, MAX_WORD_LEN as i32, &mut shifted, ptr::null())?;
+                let after = get_text(ec, &after)?;
+                let head = before
+                    .iter()
+                    .rev()
+                    .take_while(|&&ch| is_bangla_unit(ch))
+                    .count();
+                let tail = after.iter().take_while(|&&ch| is_bangla_unit(ch)).count();
+                if head + tail == 0 {
                     return Ok(());
                 }
-                // move the cursor to the end
-                self.range.Collapse(ec, TF_ANCHOR_END)?;
-                let selection = TF_SELECTION {
-                    range: ManuallyDrop::new(Some(self.range.clone())),
-                    style: TF_SELECTIONSTYLE {
-                        ase: TF_AE_NONE,
-                        fInterimChar: FALSE,
-                    },
-                };
-                self.context.SetSelection(ec, &[selection])?;
+                range.ShiftStart(ec, -(head as i32), &mut shifted, ptr::null())?;
+                range.ShiftEnd(ec, tail as i32, &mut shifted, ptr::null())?;
+                let mut text = before[before.len() - head..].to_vec();
+                text.extend_from_slice(&after[..tail]);
+                self.word
+                    .set(Some((range, String::from_utf16_lossy(&text))));
                 Ok(())
             }
         }
     }
 
+    edits.flush(tid, context)?;
     let session = ITfEditSession::from(Session {
         context,
         range,
-        text,
-        dispaly_attribute,
+        word: Cell::new(None),
     });
     unsafe {
-        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        let result = context.RequestEditSession(tid, &session, TF_ES_READ | TF_ES_SYNC)?;
         if result != S_OK {
             Err(result.into())
         } else {
-            Ok(())
+            let session: &Session = session.as_impl();
+            Ok(session.word.take())
         }
     }
 }
 
-pub fn insert_text(tid: u32, context: &ITfContext, text: &[u16]) -> Result<()> {
-    //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-
+/// The selected text, unless nothing or more than `MAX_SELECTION_LEN` UTF-16 units are selected.
+pub fn get_selected_text(
+    edits: &EditQueue,
+    tid: u32,
+    context: &ITfContext,
+) -> Result<Option<(ITfRange, String)>> {
     #[implement(ITfEditSession)]
     struct Session<'a> {
         context: &'a ITfContext,
-        text: &'a [u16],
+        selection: Cell<Option<(ITfRange, String)>>, // out
     }
 
     impl ITfEditSession_Impl for Session<'_> {
@@ -178,69 +228,217 @@ pub fn insert_text(tid: u32, context: &ITfContext, text: &[u16]) -> Result<()> {
                     .context
                     .cast::<ITfInsertAtSelection>()?
                     .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
-                // insert text via InsertTextAtSelection directly would crash the client
-                // what's wrong with these magical APIs
-                range.SetText(ec, TF_ST_CORRECTION, self.text)?;
-                range.Collapse(ec, TF_ANCHOR_END)?;
-                let selection = TF_SELECTION {
-                    range: ManuallyDrop::new(Some(range.clone())),
-                    style: TF_SELECTIONSTYLE {
-                        ase: TF_AE_NONE,
-                        fInterimChar: FALSE,
-                    },
-                };
-                self.context.SetSelection(ec, &[selection])
+                if range.IsEmpty(ec)?.as_bool() {
+                    return Ok(());
+                }
+                // read chunk by chunk, each read moves the start of the cursor forward
+                let cursor = range.Clone()?;
+                let mut text = Vec::new();
+                let mut buf = [0; 256];
+                loop {
+                    let mut len = 0;
+                    cursor.GetText(ec, TF_TF_MOVESTART, &mut buf, &mut len)?;
+                    text.extend_from_slice(&buf[..len as usize]);
+                    if text.len() > MAX_SELECTION_LEN {
+                        return Ok(());
+                    }
+                    if (len as usize) < buf.len() {
+                        break;
+                    }
+                }
+                self.selection
+                    .set(Some((range, String::from_utf16_lossy(&text))));
+                Ok(())
             }
         }
     }
 
-    let session = ITfEditSession::from(Session { context, text });
+    edits.flush(tid, context)?;
+    let session = ITfEditSession::from(Session {
+        context,
+        selection: Cell::new(None),
+    });
     unsafe {
-        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        let result = context.RequestEditSession(tid, &session, TF_ES_READ | TF_ES_SYNC)?;
         if result != S_OK {
             Err(result.into())
         } else {
-            Ok(())
+            let session: &Session = session.as_impl();
+            Ok(session.selection.take())
         }
     }
 }
 
-pub fn get_pos(tid: u32, context: &ITfContext, range: &ITfRange) -> Result<(i32, i32)> {
-    //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+/// Up to `max` UTF-16 units of text right before the selection.
+pub fn get_text_before(
+    edits: &EditQueue,
+    tid: u32,
+    context: &ITfContext,
+    max: usize,
+) -> Result<String> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        context: &'a ITfContext,
+        max: usize,
+        text: Cell<String>, // out
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = self
+                    .context
+                    .cast::<ITfInsertAtSelection>()?
+                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
+                range.Collapse(ec, TF_ANCHOR_START)?;
+                let mut shifted = 0;
+                range.ShiftStart(ec, -(self.max as i32), &mut shifted, ptr::null())?;
+                let mut buf = vec![0; self.max];
+                let mut len = 0;
+                range.GetText(ec, 0, &mut buf, &mut len)?;
+                self.text
+                    .set(String::from_utf16_lossy(&buf[..len as usize]));
+                Ok(())
+            }
+        }
+    }
+
+    edits.flush(tid, context)?;
+    let session = ITfEditSession::from(Session {
+        context,
+        max,
+        text: Cell::new(String::new()),
+    });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READ | TF_ES_SYNC)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            let session: &Session = session.as_impl();
+            Ok(session.text.take())
+        }
+    }
+}
 
+/// Whether `range` still holds `text` with the caret right behind it, i.e. nothing happened
+/// to the text since it was committed.
+pub fn is_caret_after(
+    edits: &EditQueue,
+    tid: u32,
+    context: &ITfContext,
+    range: &ITfRange,
+    text: &str,
+) -> Result<bool> {
     #[implement(ITfEditSession)]
     struct Session<'a> {
         context: &'a ITfContext,
         range: &'a ITfRange,
-        pos: Cell<(i32, i32)>,
+        text: Vec<u16>,
+        result: Cell<bool>, // out
     }
 
     impl ITfEditSession_Impl for Session<'_> {
         #[allow(non_snake_case)]
         fn DoEditSession(&self, ec: u32) -> Result<()> {
             unsafe {
-                let mut rect = RECT::default();
-                let mut clipped = BOOL::default();
-                let view = self.context.GetActiveView()?;
-                view.GetTextExt(ec, self.range, &mut rect, &mut clipped)?;
-                self.pos.set((rect.left, rect.bottom));
+                let selection = self
+                    .context
+                    .cast::<ITfInsertAtSelection>()?
+                    .InsertTextAtSelection(ec, TF_IAS_QUERYONLY, &[])?;
+                if !selection.IsEmpty(ec)?.as_bool()
+                    || selection.CompareStart(ec, self.range, TF_ANCHOR_END)? != 0
+                {
+                    return Ok(());
+                }
+                self.result.set(get_text(ec, self.range)? == self.text);
                 Ok(())
             }
         }
     }
 
+    let text = OsString::from(text).to_wchars();
+    if text.len() > MAX_WORD_LEN {
+        return Ok(false);
+    }
+    edits.flush(tid, context)?;
     let session = ITfEditSession::from(Session {
         context,
         range,
-        pos: Cell::new((0, 0)),
+        text,
+        result: Cell::new(false),
     });
     unsafe {
-        let result = context.RequestEditSession(tid, &session, TF_ES_READWRITE)?;
+        let result = context.RequestEditSession(tid, &session, TF_ES_READ | TF_ES_SYNC)?;
         if result != S_OK {
             Err(result.into())
         } else {
             let session: &Session = session.as_impl();
-            Ok(session.pos.take())
+            Ok(session.result.get())
+        }
+    }
+}
+
+/// `range` without its last `len` UTF-16 units.
+pub fn trim_end(
+    edits: &EditQueue,
+    tid: u32,
+    context: &ITfContext,
+    range: &ITfRange,
+    len: usize,
+) -> Result<ITfRange> {
+    #[implement(ITfEditSession)]
+    struct Session<'a> {
+        range: &'a ITfRange,
+        len: usize,
+        trimmed: Cell<Option<ITfRange>>, // out
+    }
+
+    impl ITfEditSession_Impl for Session<'_> {
+        #[allow(non_snake_case)]
+        fn DoEditSession(&self, ec: u32) -> Result<()> {
+            unsafe {
+                let range = self.range.Clone()?;
+                let mut shifted = 0;
+                range.ShiftEnd(ec, -(self.len as i32), &mut shifted, ptr::null())?;
+                self.trimmed.set(Some(range));
+                Ok(())
+            }
+        }
+    }
+
+    edits.flush(tid, context)?;
+    let session = ITfEditSession::from(Session {
+        range,
+        len,
+        trimmed: Cell::new(None),
+    });
+    unsafe {
+        let result = context.RequestEditSession(tid, &session, TF_ES_READ | TF_ES_SYNC)?;
+        if result != S_OK {
+            Err(result.into())
+        } else {
+            let session: &Session = session.as_impl();
+            session.trimmed.take().ok_or(E_FAIL.into())
         }
     }
 }
+
+/// Selections longer than this (in UTF-16 units) are left alone.
+const MAX_SELECTION_LEN: usize = 8192;
+
+/// Words longer than this (in UTF-16 units) are not looked for nor reconverted.
+const MAX_WORD_LEN: usize = 32;
+
+/// Read up to `MAX_WORD_LEN + 1` UTF-16 units of `range`, enough to tell a word from a longer text.
+fn get_text(ec: u32, range: &ITfRange) -> Result<Vec<u16>> {
+    let mut buf = [0; MAX_WORD_LEN + 1];
+    let mut len = 0;
+    unsafe { range.GetText(ec, 0, &mut buf, &mut len)? };
+    Ok(buf[..len as usize].to_vec())
+}
+
+// Bangla is within the BMP so every char of it is a single UTF-16 unit
+fn is_bangla_unit(unit: u16) -> bool {
+    char::from_u32(unit as u32).is_some_and(romanize::is_bangla)
+}
@@ -0,0 +1,106 @@
+use log::trace;
+use windows::{
+    Win32::UI::TextServices::{ITfContext, ITfDocumentMgr},
+    core::Result,
+};
+
+use super::TextServiceInner;
+use crate::conf::FocusAction;
+
+//----------------------------------------------------------------------------
+//
+//  Focus changes. When the focus moves to another document the thread
+//  manager tells, and the session is parked with its composition intact.
+//  When it leaves the application altogether, e.g. for a notification, the
+//  key event sink tells, and the composition is dealt with as the user has
+//  chosen. A suspended composition is kept in the session of its context,
+//  so that every document can have one waiting for the focus to return.
+//
+//----------------------------------------------------------------------------
+
+/// A composition put aside until the focus returns to its document.
+pub struct Suspended {
+    roman: Vec<char>,
+    caret: Option<usize>,
+}
+
+impl TextServiceInner {
+    /// The focus left the application.
+    pub fn lose_focus(&mut self) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        // a session still not granted by now may never be
+        if let Some(context) = &self.context {
+            self.edits.drop_pending(context);
+        }
+        if self.session.composition.is_none() || self.session.suggestions.is_none() {
+            return self.abort();
+        }
+        match self.focus_action {
+            FocusAction::Abort => self.abort(),
+            FocusAction::Commit => {
+                self.commit(None)?;
+                // there's no telling what happens to the text while the focus is away
+                self.session.last_commit = None;
+                Ok(())
+            }
+            FocusAction::Suspend => {
+                if self.context.is_some() && self.is_preedit_editable() {
+                    self.suspend()
+                } else {
+                    // riti can't be given back a preedit that isn't roman
+                    self.abort()
+                }
+            }
+        }
+    }
+
+    /// The focus moved to `document_mgr`, None if it's unknown.
+    pub fn gain_focus(&mut self, document_mgr: Option<&ITfDocumentMgr>) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        let Some(context) =
+            document_mgr.and_then(|document_mgr| unsafe { document_mgr.GetTop().ok() })
+        else {
+            return Ok(());
+        };
+        // a context that was never typed into has nothing to bring back
+        if self.context.as_ref() != Some(&context)
+            && !self.sessions.iter().any(|(parked, _)| parked == &context)
+        {
+            return Ok(());
+        }
+        self.switch_context(&context)?;
+        if self.session.composition.is_some() {
+            return Ok(());
+        }
+        match self.session.suspended.take() {
+            Some(suspended) => self.resume(&context, suspended),
+            None => Ok(()),
+        }
+    }
+
+    fn suspend(&mut self) -> Result<()> {
+        trace!("Suspending the composition of {}.", self.session.preedit);
+        self.session.suspended = Some(Suspended {
+            roman: self.session.preedit.chars().collect(),
+            caret: self.session.caret,
+        });
+        self.session.last_commit = None;
+        self.set_text("")?;
+        self.end_composition()
+    }
+
+    /// Bring a suspended composition back at the selection of its document.
+    fn resume(&mut self, context: &ITfContext, suspended: Suspended) -> Result<()> {
+        let Some(suggestion) = self.type_roman(&suspended.roman) else {
+            self.riti.finish_input_session();
+            return Ok(());
+        };
+        trace!("Resuming the composition.");
+        self.read_hints(context);
+        self.start_composition(None)?;
+        self.session.caret = suspended.caret;
+        self.apply_suggestion(suggestion)
+    }
+}
@@ -0,0 +1,115 @@
+use log::debug;
+use windows::Win32::UI::TextServices::*;
+
+use super::{Mode, TextServiceInner, edit_session};
+use crate::conf::Settings;
+
+//----------------------------------------------------------------------------
+//
+//  Applications tell what kind of text a field expects through input scopes.
+//  Password fields are "secure": keys typed into them are passed through
+//  untouched, never logged and never shown to riti. Fields for URLs, e-mail
+//  addresses, numbers and the like start in English mode.
+//
+//----------------------------------------------------------------------------
+
+/// Input scopes with an opinion on the mode, named as in `settings\InputScope`.
+/// The flag tells whether English is the default for the scope. For the others the
+/// user's mode is kept unless overridden in settings.
+const SCOPES: [(&str, InputScope, bool); 36] = [
+    ("Url", IS_URL, true),
+    ("FullFilePath", IS_FILE_FULLFILEPATH, true),
+    ("FileName", IS_FILE_FILENAME, true),
+    ("EmailUserName", IS_EMAIL_USERNAME, true),
+    ("EmailAddress", IS_EMAIL_SMTPEMAILADDRESS, true),
+    ("EmailNameOrAddress", IS_EMAILNAME_OR_ADDRESS, true),
+    ("LoginName", IS_LOGINNAME, true),
+    ("Number", IS_NUMBER, true),
+    ("NumberFullWidth", IS_NUMBER_FULLWIDTH, true),
+    ("Digits", IS_DIGITS, true),
+    ("TelephoneNumber", IS_TELEPHONE_FULLTELEPHONENUMBER, true),
+    ("TelephoneCountryCode", IS_TELEPHONE_COUNTRYCODE, true),
+    ("TelephoneAreaCode", IS_TELEPHONE_AREACODE, true),
+    ("TelephoneLocalNumber", IS_TELEPHONE_LOCALNUMBER, true),
+    ("PostalCode", IS_ADDRESS_POSTALCODE, true),
+    ("CurrencyAmount", IS_CURRENCY_AMOUNT, true),
+    ("FullDate", IS_DATE_FULLDATE, true),
+    ("Day", IS_DATE_DAY, true),
+    ("Month", IS_DATE_MONTH, true),
+    ("Year", IS_DATE_YEAR, true),
+    ("FullTime", IS_TIME_FULLTIME, true),
+    ("Hour", IS_TIME_HOUR, true),
+    ("MinuteOrSecond", IS_TIME_MINORSEC, true),
+    ("AlphanumericHalfWidth", IS_ALPHANUMERIC_HALFWIDTH, true),
+    ("AlphanumericPin", IS_ALPHANUMERIC_PIN, true),
+    ("NumericPin", IS_NUMERIC_PIN, true),
+    ("Formula", IS_FORMULA, true),
+    ("FormulaNumber", IS_FORMULA_NUMBER, true),
+    ("RegularExpression", IS_REGULAREXPRESSION, true),
+    ("Xml", IS_XML, true),
+    ("Srgs", IS_SRGS, true),
+    ("Text", IS_TEXT, false),
+    ("Search", IS_SEARCH, false),
+    ("Chat", IS_CHAT, false),
+    ("PersonalName", IS_PERSONALNAME_FULLNAME, false),
+    ("NativeScript", IS_NATIVE_SCRIPT, false),
+];
+
+impl TextServiceInner {
+    /// Whether keys typed into `context` must be left alone. It's true for password fields and
+    /// for every field when the text service was activated in secure mode (e.g. the logon screen).
+    ///
+    /// Transitory contexts (`TF_SS_TRANSITORY`) are not considered secure on their own since
+    /// plain edit controls of legacy programs are transitory as well.
+    ///
+    /// Nothing is looked up in applications the input method is disabled for, as their keys
+    /// are left alone anyway.
+    pub fn is_secure(&mut self, context: Option<&ITfContext>) -> bool {
+        if self.secure_mode {
+            return true;
+        }
+        let Some(context) = context.filter(|_| !self.disabled) else {
+            return false;
+        };
+        if self.scoped_context.as_ref() != Some(context) {
+            self.apply_input_scopes(context);
+        }
+        self.secure
+    }
+
+    /// Look up the input scopes of a newly focused context. The mode forced by the previous
+    /// context is dropped so the user's own mode comes back.
+    fn apply_input_scopes(&mut self, context: &ITfContext) {
+        let scopes =
+            edit_session::get_input_scopes(&self.edits, self.tid, context).unwrap_or_default();
+        let secure = scopes.contains(&IS_PASSWORD);
+        if secure != self.secure {
+            debug!("Secure field: {secure}.");
+        }
+        if secure && self.session.composition.is_some() {
+            let _ = self.abort();
+        }
+        self.secure = secure;
+        self.scope_mode = if secure { None } else { scope_mode(&scopes) };
+        if self.scope_mode.is_some() {
+            debug!("Mode forced by input scope: {:?}.", self.scope_mode);
+        }
+        self.scoped_context = Some(context.clone());
+    }
+}
+
+/// The mode for the first of `scopes` that has an opinion on it.
+fn scope_mode(scopes: &[InputScope]) -> Option<Mode> {
+    if scopes.is_empty() {
+        return None;
+    }
+    let settings = Settings::load_or_create().ok();
+    scopes.iter().find_map(|scope| {
+        let (name, _, english) = SCOPES.iter().find(|(_, s, _)| s == scope)?;
+        let english = settings
+            .as_ref()
+            .and_then(|settings| settings.get_input_scope_english(name))
+            .or(english.then_some(true))?;
+        Some(if english { Mode::English } else { Mode::Bangla })
+    })
+}
@@ -1,28 +1,25 @@
+use std::time::Instant;
+
 use Input::*;
 use log::{trace, warn};
-use riti::context::{MODIFIER_ALT_GR, MODIFIER_SHIFT};
 use windows::{
     Win32::{
-        Foundation::{BOOL, E_FAIL, FALSE, LPARAM, TRUE, WPARAM},
+        Foundation::{BOOL, E_FAIL, FALSE, LPARAM, WPARAM},
         UI::{
-            Input::KeyboardAndMouse::{
-                GetKeyboardState, MAPVK_VK_TO_VSC, MapVirtualKeyExW, ToUnicodeEx, VK_CONTROL,
-                VK_LCONTROL, VK_LMENU, VK_LSHIFT, VK_MENU, VK_RCONTROL, VK_RMENU, VK_RSHIFT,
-                VK_SHIFT,
-            },
-            TextServices::{
-                ITfContext, ITfKeyEventSink_Impl, ITfKeystrokeMgr, TF_MOD_ALT, TF_MOD_CONTROL,
-                TF_MOD_SHIFT, TF_PRESERVEDKEY,
-            },
+            Input::KeyboardAndMouse::VK_RMENU,
+            TextServices::{ITfContext, ITfKeyEventSink_Impl},
         },
     },
     core::{GUID, Result},
 };
 
-use super::{TextService, TextServiceInner};
+use super::{Mode, TextService, TextServiceInner, preserved_keys::PreservedKey};
 use crate::{
-    extend::{CharExt, GUIDExt, VKExt},
-    tsf::keycode::{UNKNOWN_KEYCODE, to_keycode},
+    conf::{EnterAction, Settings},
+    extend::{GUIDExt, ResultExt, VKExt},
+    global::CANDI_NUM,
+    key_event::{Decision, Input, State, decide},
+    keymap::Action,
 };
 //----------------------------------------------------------------------------
 //
@@ -45,20 +42,28 @@ impl ITfKeyEventSink_Impl for TextService {
     ///
     /// `wparam` indicates the key that is pressed.
     /// The 0-15 bits of `_lparam` indicates the repeat count (ignored here because it's actually always 1).
+    /// The 16-23 bits are the scan code, telling where the key is on the keyboard.
+    /// The 30th bit is set if the key was down already, i.e. auto-repeat.
     /// (See https://learn.microsoft.com/en-us/windows/win32/inputdev/wm-keydown for detail).
     fn OnTestKeyDown(
         &self,
-        _context: Option<&ITfContext>,
+        context: Option<&ITfContext>,
         wparam: WPARAM,
         lparam: LPARAM,
     ) -> Result<BOOL> {
+        let mut inner = self.write()?;
+        if inner.is_secure(context) {
+            return Ok(FALSE);
+        }
         trace!("OnTestKeyDown({:#04X})", wparam.0);
 
-        let inner = self.write()?;
-
-        let input = inner.parse_input(wparam.0 as u32, lparam.0 as u32)?;
+        let event = inner.translate_key(wparam.0 as u32, lparam)?;
+        inner
+            .taps
+            .key_down(event.code, event.repeat, Instant::now());
+        let input = Input::from_event(&event, &inner.keymap);
 
-        inner.test_input(input)
+        inner.test_input(input, context)
     }
 
     /// The return value suggests if the key event **is** eaten or not.
@@ -71,168 +76,112 @@ impl ITfKeyEventSink_Impl for TextService {
         wparam: WPARAM,
         lparam: LPARAM,
     ) -> Result<BOOL> {
-        trace!("OnKeyDown({:#04X})", wparam.0);
-
         let mut inner = self.write()?;
+        if inner.is_secure(context) {
+            return Ok(FALSE);
+        }
+        trace!("OnKeyDown({:#04X})", wparam.0);
 
-        let input = inner.parse_input(wparam.0 as u32, lparam.0 as u32)?;
+        let event = inner.translate_key(wparam.0 as u32, lparam)?;
+        inner
+            .taps
+            .key_down(event.code, event.repeat, Instant::now());
+        let input = Input::from_event(&event, &inner.keymap);
 
         inner.handle_input(input, context)
     }
 
-    /// Flip the modifiers back
+    /// Key ups only matter to tell a modifier tapped on its own, see tap.rs.
+    /// Nothing changes until `OnKeyUp`, which may or may not follow.
     fn OnTestKeyUp(
         &self,
-        _context: Option<&ITfContext>,
+        context: Option<&ITfContext>,
         wparam: WPARAM,
         _lparam: LPARAM,
     ) -> Result<BOOL> {
+        let mut inner = self.write()?;
+        if inner.is_secure(context) {
+            return Ok(FALSE);
+        }
         trace!("OnTestKeyUp({:#04X})", wparam.0);
 
-        Ok(FALSE)
+        match inner.taps.peek(wparam.0 as u32, Instant::now()) {
+            Some(modifier) => inner.test_input(Tap(modifier), context),
+            None => Ok(FALSE),
+        }
     }
 
     fn OnKeyUp(
         &self,
-        _context: Option<&ITfContext>,
+        context: Option<&ITfContext>,
         wparam: WPARAM,
         _lparam: LPARAM,
     ) -> Result<BOOL> {
+        let mut inner = self.write()?;
+        if inner.is_secure(context) {
+            return Ok(FALSE);
+        }
         trace!("OnKeyUp({:#04X})", wparam.0);
 
-        Ok(FALSE)
+        match inner.taps.key_up(wparam.0 as u32, Instant::now()) {
+            Some(modifier) => inner.handle_input(Tap(modifier), context),
+            None => Ok(FALSE),
+        }
     }
 
     fn OnPreservedKey(&self, context: Option<&ITfContext>, rguid: *const GUID) -> Result<BOOL> {
-        let guid = unsafe { rguid.as_ref() }.ok_or(E_FAIL)?;
-        let Some((vkey, shift)) = decode_preserved_key_guid(guid) else {
-            trace!("OnPreservedKey: unknown GUID {:?}", GUID::to_rfc4122(guid));
+        let mut inner = self.write()?;
+        if inner.is_secure(context) {
             return Ok(FALSE);
+        }
+        let guid = unsafe { rguid.as_ref() }.ok_or(E_FAIL)?;
+        // the key sink never sees the key, which makes any modifier held a chord
+        inner.taps.interrupt();
+        let (vkey, shift) = match inner.preserved_keys.lookup(guid) {
+            Some(PreservedKey::Hotkey(action)) => {
+                let Some(context) = context else {
+                    return Ok(FALSE);
+                };
+                return inner.handle_hotkey(action, context);
+            }
+            // TSF may not tell the sides of Alt apart, Ctrl+Alt goes on to the application
+            Some(PreservedKey::AltGr { .. }) if inner.right_alt_only && !VK_RMENU.is_down() => {
+                return Ok(FALSE);
+            }
+            Some(PreservedKey::AltGr { vkey, shift }) => (vkey, shift),
+            None => {
+                trace!("OnPreservedKey: unknown GUID {:?}", GUID::to_rfc4122(guid));
+                return Ok(FALSE);
+            }
         };
 
         trace!("OnPreservedKey: vkey={:#04X}, shift={}", vkey, shift);
 
-        let mut inner = self.write()?;
-        let scancode = unsafe { MapVirtualKeyExW(vkey, MAPVK_VK_TO_VSC, inner.hkl) };
-        let char_key = inner.parse_character_key(vkey, scancode)?;
-
-        let input = match char_key {
-            Key(key) => {
-                if shift {
-                    ShiftAltGr(key)
-                } else {
-                    AltGrKey(key)
-                }
-            }
-            Number(n) => {
-                if shift {
-                    ShiftAltGr(n as u16)
-                } else {
-                    AltGrKey(n as u16)
-                }
-            }
-            _ => return Ok(FALSE),
-        };
+        let event = inner.translate_altgr(vkey, shift)?;
+        let input = Input::from_event(&event, &inner.keymap);
+        if !matches!(input, AltGrKey(_) | ShiftAltGr(_)) {
+            return Ok(FALSE);
+        }
 
         inner.handle_input(input, context)
     }
 
     fn OnSetFocus(&self, foreground: BOOL) -> Result<()> {
         trace!("OnSetFocus({})", foreground.as_bool());
+        let mut inner = self.write()?;
+        let focus = unsafe { inner.thread_mgr()?.GetFocus().ok() };
         if !foreground.as_bool() {
-            self.write()?.abort()
+            inner.lose_focus()
         } else {
-            Ok(())
-        }
-    }
-}
-
-impl TextServiceInner {
-    fn parse_input(&self, keycode: u32, scancode: u32) -> Result<Input> {
-        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-        let ctrl = VK_CONTROL.is_down() || VK_LCONTROL.is_down() || VK_RCONTROL.is_down();
-
-        let input = match keycode {
-            0x08 => Backspace(ctrl),
-            0x09 => Tab,
-            0x0D => Enter,
-            0x20 => Space,
-            0x25 => Left,
-            0x26 => Up,
-            0x27 => Right,
-            0x28 => Down,
-            keycode @ 0x00..0x20 | keycode @ 0x7F => Unknown(keycode),
-            keycode => {
-                let key = self.parse_character_key(keycode, scancode)?;
-
-                // Encode the key as unknown if Ctrl is pressed
-                if let Key(key) = key && ctrl {
-                    Unknown(key as u32)
-                } else if let Number(key) = key && ctrl {
-                    Unknown(key as u32)
-                } else {
-                    key
-                }
-            }
-        };
-        Ok(input)
-    }
-
-    fn parse_character_key(&self, keycode: u32, scancode: u32) -> Result<Input> {
-        let hkl = self.hkl;
-        let mut buf = [0; 8];
-        let mut keyboard_state = [0; 256];
-        let ret = unsafe {
-            GetKeyboardState(&mut keyboard_state)?;
-            // Clear Ctrl and Alt so ToUnicodeEx translates the base character.
-            // We detect these modifiers separately via VK_CONTROL/VK_MENU.is_down().
-            keyboard_state[VK_CONTROL.0 as usize] = 0;
-            keyboard_state[VK_LCONTROL.0 as usize] = 0;
-            keyboard_state[VK_RCONTROL.0 as usize] = 0;
-            keyboard_state[VK_MENU.0 as usize] = 0;
-            keyboard_state[VK_LMENU.0 as usize] = 0;
-            keyboard_state[VK_RMENU.0 as usize] = 0;
-            ToUnicodeEx(keycode, scancode, &keyboard_state, &mut buf, 0, hkl)
-        };
-        if ret == 0 {
-            return Ok(Unknown(keycode));
-        }
-        let Ok(ch) = char::try_from_utf16(buf[0]) else {
-            return Ok(Unknown(keycode));
-        };
-        match ch {
-            number @ '0'..='9' => Ok(Number(number as usize - '0' as usize)),
-            _ => {
-                let kc = to_keycode(ch, keycode);
-                if kc != UNKNOWN_KEYCODE {
-                    Ok(Key(kc))
-                } else {
-                    Ok(Unknown(kc as u32))
-                }
+            // the layout may have been changed in the settings meanwhile
+            if let Ok(settings) = Settings::load_or_create() {
+                inner.refresh_preserved_keys(&settings);
             }
+            inner.gain_focus(focus.as_ref())
         }
     }
 }
 
-/// Inputs that are easier to understand and handle.
-/// See https://learn.microsoft.com/en-us/windows/win32/inputdev/virtual-key-codes for keycodes.
-#[derive(Debug, Clone, Copy)]
-enum Input {
-    Number(usize),
-    Key(u16),
-    AltGrKey(u16),
-    ShiftAltGr(u16),
-    Space,
-    Backspace(bool), // is Ctrl
-    Enter,
-    Tab,
-    Left,
-    Up,
-    Right,
-    Down,
-    Unknown(#[allow(dead_code)] u32),
-}
-
 //----------------------------------------------------------------------------
 //
 //  After simplifying the overly-complicated events, we can start handling them.
@@ -241,20 +190,19 @@ enum Input {
 //----------------------------------------------------------------------------
 
 impl TextServiceInner {
-    fn test_input(&self, input: Input) -> Result<BOOL> {
+    fn test_input(&mut self, input: Input, context: Option<&ITfContext>) -> Result<BOOL> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
         trace!("test_input({:#04X?})", input);
-        if self.composition.is_none() {
-            match input {
-                Key(_) => Ok(TRUE),
-                AltGrKey(_) => Ok(TRUE),
-                ShiftAltGr(_) => Ok(TRUE),
-                _ => Ok(FALSE),
-            }
-        } else {
-            Ok(TRUE)
+        if self.disabled {
+            return Ok(FALSE);
         }
+        if let Some(context) = context {
+            self.switch_context(context)?;
+        }
+        // nothing changes until handle_input, which may never be called
+        let decision = decide(input, &self.key_state(), &self.keymap);
+        Ok((decision != Decision::Pass).into())
     }
 
     fn handle_input(&mut self, input: Input, context: Option<&ITfContext>) -> Result<BOOL> {
@@ -267,153 +215,194 @@ impl TextServiceInner {
         );
 
         trace!("handle_input({:?})", input);
+        if self.disabled {
+            return Ok(FALSE);
+        }
         let Some(context) = context else {
             warn!("Context is None");
             return Ok(FALSE);
         };
-        self.context = Some(context.clone());
-        if self.composition.is_none() {
-            match input {
-                // letters start compositions. punctuators need to be re-mapped.
-                Key(key) => {
+        self.switch_context(context)?;
+        let decision = decide(input, &self.key_state(), &self.keymap);
+        trace!("{decision:?}");
+        // the preview and the commit to take back only last until the next key
+        if self.preview.is_some()
+            && !matches!(decision, Decision::ConfirmPreview | Decision::CancelPreview)
+        {
+            self.cancel_preview()?;
+        }
+        if decision != Decision::UndoCommit {
+            self.session.last_commit = None;
+        }
+        let eaten = match decision {
+            Decision::Pass => false,
+            Decision::Ignore => true,
+            Decision::Hotkey(action) => return self.handle_hotkey(action, context),
+            Decision::Action(action) => {
+                self.handle_action(action)?;
+                true
+            }
+            Decision::ConfirmPreview => {
+                self.confirm_preview()?;
+                true
+            }
+            Decision::CancelPreview => {
+                self.cancel_preview()?;
+                true
+            }
+            Decision::UndoCommit => self.undo_commit(context)?,
+            Decision::Type(key, modifier) => {
+                if self.session.composition.is_none() {
                     log::trace!("Starting composition");
+                    self.read_hints(context);
                     self.update_engine();
 
-                    self.start_composition()?;
-                    self.keypress(key, 0)?
+                    self.start_composition(None)?;
                 }
-                AltGrKey(key) => {
-                    log::trace!("Starting composition");
-                    self.update_engine();
-
-                    self.start_composition()?;
-                    self.keypress(key, MODIFIER_ALT_GR)?
+                self.keypress(key, modifier)?;
+                true
+            }
+            Decision::Select(index) => {
+                self.select(index, None)?;
+                true
+            }
+            Decision::Commit(append) => {
+                self.commit(append)?;
+                true
+            }
+            Decision::CommitAndPass => {
+                self.commit(None)?;
+                false
+            }
+            Decision::Enter(shift) => {
+                let action = if shift {
+                    self.shift_enter_action
+                } else {
+                    self.enter_action
+                };
+                match action {
+                    EnterAction::CommitNewline => self.commit(Some('\n'))?,
+                    EnterAction::Commit => self.commit(None)?,
+                    EnterAction::CommitRaw => self.release()?,
                 }
-                ShiftAltGr(key) => {
-                    log::trace!("Starting composition");
-                    self.update_engine();
+                true
+            }
+            Decision::Pop(ctrl) => {
+                self.pop(ctrl)?;
+                true
+            }
+            Decision::Delete => {
+                self.delete()?;
+                true
+            }
+            Decision::SetCaret(caret) => {
+                self.set_caret(caret)?;
+                true
+            }
+            Decision::MoveCaret(offset) => {
+                self.move_caret(offset)?;
+                true
+            }
+        };
+        Ok(eaten.into())
+    }
 
-                    self.start_composition()?;
-                    self.keypress(key, MODIFIER_SHIFT ^ MODIFIER_ALT_GR)?
+    /// What the text service is in the middle of, for `decide`.
+    fn key_state(&self) -> State {
+        State {
+            composing: self.session.composition.is_some(),
+            english: self.mode() == Mode::English,
+            previewing: self.preview.is_some(),
+            can_undo: self.can_undo_by_backspace(),
+            has_candidates: !self.session.candidates.is_empty(),
+            preedit_editable: self.is_preedit_editable(),
+            number_pad: self.number_pad,
+            vertical_candidates: self.is_candidate_list_vertical(),
+        }
+    }
+
+    /// Act on the composition as bound in the keymap.
+    fn handle_action(&mut self, action: Action) -> Result<()> {
+        trace!("handle_action({:?})", action);
+        match action {
+            Action::Commit => self.commit(None)?,
+            Action::CommitRaw => self.release()?,
+            Action::Cancel => self.cancel()?,
+            Action::NextCandidate => {
+                if let Ok(candidate_list) = self.candidate_list() {
+                    candidate_list.move_highlight_next();
                 }
-                _ => return Ok(FALSE),
             }
-        } else {
-            match input {
-                Number(0) => (),
-                Number(number) => self.select(number - 1, None)?,
-                Key(key) => self.keypress(key, 0)?,
-                AltGrKey(key) => self.keypress(key, MODIFIER_ALT_GR)?,
-                ShiftAltGr(key) => self.keypress(key, MODIFIER_SHIFT ^ MODIFIER_ALT_GR)?,
-                Space => {
-                    self.commit(Some(' '))?;
+            Action::PrevCandidate => {
+                if let Ok(candidate_list) = self.candidate_list() {
+                    candidate_list.move_highlight_prev();
                 }
-                Enter => {
-                    // self.release()?;
-                    self.commit(Some('\n'))?;
-                }
-                Backspace(ctrl) => self.pop(ctrl)?,
-                Left | Up => {
-                    if let Ok(candidate_list) = self.candidate_list() {
-                        candidate_list.move_highlight_prev();
-                    }
-                }
-                Tab | Right | Down => {
-                    if let Ok(candidate_list) = self.candidate_list() {
-                        candidate_list.move_highlight_next();
-                    }
+            }
+            // the candidate list has no pages of its own, a page is as many as it shows
+            Action::NextPage => {
+                if let Ok(candidate_list) = self.candidate_list() {
+                    let last = candidate_list.get_candidate_count().saturating_sub(1);
+                    let index = candidate_list.get_highlighted_index() + CANDI_NUM;
+                    candidate_list.set_highlight(index.min(last));
                 }
-                _ => {
-                    return Ok(FALSE);
+            }
+            Action::PrevPage => {
+                if let Ok(candidate_list) = self.candidate_list() {
+                    let index = candidate_list.get_highlighted_index();
+                    candidate_list.set_highlight(index.saturating_sub(CANDI_NUM));
                 }
             }
+            // the others are hotkeys, see handle_hotkey
+            _ => (),
         }
-        Ok(TRUE)
-    }
-
-    // fn insert_char(&mut self, ch: char) -> Result<()> {
-    //     //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
-
-    //     self.char_buf.clear();
-    //     self.char_buf.push(ch);
-    //     let text = OsString::from(&self.char_buf).to_wchars();
-    //     edit_session::insert_text(self.tid, self.context()?, &text)
-    // }
-}
-
-//----------------------------------------------------------------------------
-//
-//  Preserved keys: Ctrl+Alt combinations are delivered via WM_SYSKEYDOWN
-//  which TSF does not forward to the key event sink. We register them as
-//  preserved keys so they arrive via OnPreservedKey instead.
-//
-//----------------------------------------------------------------------------
-
-/// Encode virtual key code and shift flag into a GUID for preserved key identification.
-const fn preserved_key_guid(vkey: u8, shift: bool) -> GUID {
-    GUID {
-        data1: 0xCAFEBA0E,
-        data2: vkey as u16,
-        data3: if shift { 1 } else { 0 },
-        data4: [0x9A, 0xC4, 0x75, 0xF8, 0x42, 0x29, 0x47, 0xF5],
+        Ok(())
     }
-}
 
-/// Decode a preserved key GUID back to (vkey, shift).
-fn decode_preserved_key_guid(guid: &GUID) -> Option<(u32, bool)> {
-    if guid.data1 == 0xCAFEBA0E && guid.data4 == [0x9A, 0xC4, 0x75, 0xF8, 0x42, 0x29, 0x47, 0xF5] {
-        Some((guid.data2 as u32, guid.data3 != 0))
-    } else {
-        None
-    }
-}
-
-/// Virtual key codes to register as Ctrl+Alt preserved keys.
-const PRESERVED_VKEYS: &[u32] = &[
-    // A-Z
-    0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x4B, 0x4C, 0x4D, 0x4E, 0x4F, 0x50,
-    0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 
-    // 0-9
-    0x30, 0x31, 0x32, 0x33, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39,
-    // OEM keys: ;: =+ ,< -_ .> /? `~ [{ \| ]} '"
-    0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF, 0xC0, 0xDB, 0xDC, 0xDD, 0xDE,
-];
-
-pub fn register_preserved_keys(keystroke_mgr: &ITfKeystrokeMgr, tid: u32) {
-    for &vkey in PRESERVED_VKEYS {
-        for shift in [false, true] {
-            let guid = preserved_key_guid(vkey as u8, shift);
-            let mut modifiers = TF_MOD_CONTROL | TF_MOD_ALT;
-            if shift {
-                modifiers |= TF_MOD_SHIFT;
+    fn handle_hotkey(&mut self, action: Action, context: &ITfContext) -> Result<BOOL> {
+        trace!("handle_hotkey({:?})", action);
+        self.switch_context(context)?;
+        let eaten = match action {
+            // the hotkey is a Ctrl+Backspace too, which deletes as usual while composing
+            Action::Reconversion if self.session.composition.is_some() => {
+                return self.handle_input(Backspace(true), Some(context));
             }
-            let prekey = TF_PRESERVEDKEY {
-                uVKey: vkey,
-                uModifiers: modifiers,
-            };
-            if let Err(e) = unsafe { keystroke_mgr.PreserveKey(tid, &guid, &prekey, &[]) } {
-                trace!(
-                    "Failed to register preserved key vkey={:#04X} shift={}: {}",
-                    vkey, shift, e
-                );
+            Action::Reconversion => self.reconvert(context, None)?,
+            Action::Transliteration => self.transliterate_selection(context)?,
+            Action::UndoCommit => self.undo_commit(context)?,
+            Action::ToggleMode => {
+                if self.session.composition.is_some() {
+                    self.commit(None)?;
+                }
+                // from the mode in effect, which the user's choice takes over from
+                self.mode = match self.mode() {
+                    Mode::Bangla => Mode::English,
+                    Mode::English => Mode::Bangla,
+                };
+                self.scope_mode = None;
+                trace!("Switched to {:?} mode.", self.mode);
+                true
             }
-        }
-    }
-}
-
-pub fn unregister_preserved_keys(keystroke_mgr: &ITfKeystrokeMgr) {
-    for &vkey in PRESERVED_VKEYS {
-        for shift in [false, true] {
-            let guid = preserved_key_guid(vkey as u8, shift);
-            let mut modifiers = TF_MOD_CONTROL | TF_MOD_ALT;
-            if shift {
-                modifiers |= TF_MOD_SHIFT;
+            Action::SwitchLayout => {
+                if self.session.composition.is_some() {
+                    self.commit(None)?;
+                }
+                let switched = Settings::load_or_create()
+                    .and_then(|settings| Ok(settings.switch_layout()?.then_some(settings)))
+                    .log_err()
+                    .flatten();
+                if let Some(settings) = switched {
+                    self.update_engine();
+                    // the other layout has AltGr keys of its own
+                    self.refresh_preserved_keys(&settings);
+                    trace!("Switched the layout.");
+                } else {
+                    warn!("There's no alternate layout to switch to.");
+                }
+                true
             }
-            let prekey = TF_PRESERVEDKEY {
-                uVKey: vkey,
-                uModifiers: modifiers,
-            };
-            let _ = unsafe { keystroke_mgr.UnpreserveKey(&guid, &prekey) };
-        }
+            // the others act on a composition, see handle_action
+            _ => false,
+        };
+        Ok(eaten.into())
     }
 }
@@ -0,0 +1,178 @@
+use windows::{
+    Win32::{
+        Foundation::LPARAM,
+        UI::{
+            Input::KeyboardAndMouse::{
+                GetKeyboardState, MAPVK_VK_TO_VSC_EX, MapVirtualKeyExW, ToUnicodeEx, VK_CAPITAL,
+                VK_CONTROL, VK_DIVIDE, VK_LCONTROL, VK_LMENU, VK_LSHIFT, VK_MENU, VK_NUMPAD0,
+                VK_RCONTROL, VK_RMENU, VK_RSHIFT, VK_SHIFT,
+            },
+            WindowsAndMessaging::KF_EXTENDED,
+        },
+    },
+    core::Result,
+};
+
+use super::TextServiceInner;
+use crate::{
+    extend::{CharExt, VKExt},
+    key_event::{KeyEvent, LogicalKey, Modifiers},
+    tap::Modifier,
+    tsf::keycode::{UNKNOWN_KEYCODE, position_to_char, to_keycode},
+};
+
+//----------------------------------------------------------------------------
+//
+//  Win32 key events, translated into the platform-neutral ones of
+//  key_event.rs. This is where the keyboard layout of Windows, the keyboard
+//  state and the quirks of AltGr are dealt with.
+//
+//----------------------------------------------------------------------------
+
+impl TextServiceInner {
+    /// Translate a key going down, `vkey` and `lparam` as in WM_KEYDOWN.
+    pub fn translate_key(&self, vkey: u32, lparam: LPARAM) -> Result<KeyEvent> {
+        let modifiers = held_modifiers();
+        let key = if let Some(modifier) = Modifier::from_vkey(vkey) {
+            LogicalKey::Modifier(modifier)
+        } else {
+            match vkey {
+                0x08 => LogicalKey::Backspace,
+                0x09 => LogicalKey::Tab,
+                0x0D => LogicalKey::Enter,
+                0x1B => LogicalKey::Escape,
+                0x20 => LogicalKey::Space,
+                0x21 => LogicalKey::PageUp,
+                0x22 => LogicalKey::PageDown,
+                0x23 => LogicalKey::End,
+                0x24 => LogicalKey::Home,
+                0x25 => LogicalKey::Left,
+                0x26 => LogicalKey::Up,
+                0x27 => LogicalKey::Right,
+                0x28 => LogicalKey::Down,
+                0x2E => LogicalKey::Delete,
+                0x00..0x20 | 0x7F => LogicalKey::Other(vkey),
+                // the AltGr keys of the layout are preserved keys, the others type what Windows
+                // has for them
+                _ if modifiers.altgr => LogicalKey::Other(vkey),
+                _ => match self.character(vkey, scan_code(lparam))? {
+                    Some(ch) => LogicalKey::Char(ch),
+                    None => LogicalKey::Other(vkey),
+                },
+            }
+        };
+        Ok(KeyEvent {
+            key,
+            code: vkey,
+            keycode: riti_keycode(key, vkey),
+            modifiers,
+            keypad: is_keypad(vkey),
+            repeat: is_repeat(lparam),
+        })
+    }
+
+    /// Translate a preserved key standing in for AltGr (see preserved_keys.rs), however
+    /// it was typed.
+    pub fn translate_altgr(&self, vkey: u32, shift: bool) -> Result<KeyEvent> {
+        let scancode = unsafe { MapVirtualKeyExW(vkey, MAPVK_VK_TO_VSC_EX, self.hkl) };
+        // extended keys come prefixed with 0xE0 or 0xE1, scan_code flags them instead
+        let scancode = match scancode >> 8 {
+            0xE0 | 0xE1 => (scancode & 0xFF) | KF_EXTENDED,
+            _ => scancode,
+        };
+        let key = match self.character(vkey, scancode)? {
+            Some(ch) => LogicalKey::Char(ch),
+            None => LogicalKey::Other(vkey),
+        };
+        Ok(KeyEvent {
+            key,
+            code: vkey,
+            keycode: riti_keycode(key, vkey),
+            modifiers: Modifiers {
+                ctrl: false,
+                shift,
+                alt: true,
+                altgr: true,
+            },
+            keypad: false,
+            repeat: false,
+        })
+    }
+
+    /// The character the key types, leaving Ctrl and Alt aside. None if it types nothing.
+    fn character(&self, vkey: u32, scancode: u32) -> Result<Option<char>> {
+        let hkl = self.hkl;
+        let mut buf = [0; 8];
+        let mut keyboard_state = [0; 256];
+        unsafe { GetKeyboardState(&mut keyboard_state)? };
+        if self.positional_keys
+            && !is_keypad(vkey)
+            && let Some(ch) = self.positional_char(scancode, &keyboard_state)
+        {
+            return Ok(Some(ch));
+        }
+        let ret = unsafe {
+            // Clear Ctrl and Alt so ToUnicodeEx translates the base character.
+            // We detect these modifiers separately, see held_modifiers.
+            keyboard_state[VK_CONTROL.0 as usize] = 0;
+            keyboard_state[VK_LCONTROL.0 as usize] = 0;
+            keyboard_state[VK_RCONTROL.0 as usize] = 0;
+            keyboard_state[VK_MENU.0 as usize] = 0;
+            keyboard_state[VK_LMENU.0 as usize] = 0;
+            keyboard_state[VK_RMENU.0 as usize] = 0;
+            ToUnicodeEx(vkey, scancode, &keyboard_state, &mut buf, 0, hkl)
+        };
+        if ret == 0 {
+            return Ok(None);
+        }
+        Ok(char::try_from_utf16(buf[0]).ok())
+    }
+
+    /// What the key at `scancode` types on a US keyboard, whatever the layout of Windows is.
+    /// Fixed layouts are laid out on the keys' positions, not the letters printed on them.
+    fn positional_char(&self, scancode: u32, keyboard_state: &[u8; 256]) -> Option<char> {
+        let shift = keyboard_state[VK_SHIFT.0 as usize] & 0x80 != 0;
+        let caps_lock = keyboard_state[VK_CAPITAL.0 as usize] & 0x01 != 0;
+        let ch = position_to_char(scancode, shift)?;
+        if caps_lock && ch.is_ascii_alphabetic() {
+            position_to_char(scancode, !shift)
+        } else {
+            Some(ch)
+        }
+    }
+}
+
+fn held_modifiers() -> Modifiers {
+    let altgr = VK_RMENU.is_down();
+    Modifiers {
+        // AltGr comes along with a left Ctrl that was never pressed, which doesn't count
+        ctrl: VK_RCONTROL.is_down() || (VK_LCONTROL.is_down() && !altgr),
+        shift: VK_SHIFT.is_down() || VK_LSHIFT.is_down() || VK_RSHIFT.is_down(),
+        alt: altgr || VK_LMENU.is_down(),
+        altgr,
+    }
+}
+
+fn riti_keycode(key: LogicalKey, vkey: u32) -> Option<u16> {
+    match key {
+        LogicalKey::Char(ch) => Some(to_keycode(ch, vkey)).filter(|&kc| kc != UNKNOWN_KEYCODE),
+        _ => None,
+    }
+}
+
+/// Keypad keys are the same whatever the layout is.
+fn is_keypad(vkey: u32) -> bool {
+    (VK_NUMPAD0.0 as u32..=VK_DIVIDE.0 as u32).contains(&vkey)
+}
+
+/// The scan code of a key, from the `lparam` of WM_KEYDOWN. KF_EXTENDED is kept to tell the
+/// extended keys from the keys of the main block they share a scan code with, like the
+/// keypad's / and the / key.
+fn scan_code(lparam: LPARAM) -> u32 {
+    (lparam.0 as u32 >> 16) & (0xFF | KF_EXTENDED)
+}
+
+/// Whether the key was down already, from the `lparam` of WM_KEYDOWN.
+fn is_repeat(lparam: LPARAM) -> bool {
+    lparam.0 as u32 & (1 << 30) != 0
+}
@@ -1,135 +1,304 @@
 use riti::keycodes::*;
 use windows::Win32::UI::Input::KeyboardAndMouse::{
-    VIRTUAL_KEY, VK_ADD, VK_DECIMAL, VK_DIVIDE, VK_MULTIPLY, VK_NUMPAD0, VK_NUMPAD1, VK_NUMPAD2,
-    VK_NUMPAD3, VK_NUMPAD4, VK_NUMPAD5, VK_NUMPAD6, VK_NUMPAD7, VK_NUMPAD8, VK_NUMPAD9,
-    VK_SUBTRACT,
+    VIRTUAL_KEY, VK_0, VK_1, VK_2, VK_3, VK_4, VK_5, VK_6, VK_7, VK_8, VK_9, VK_A, VK_ADD, VK_B,
+    VK_C, VK_D, VK_DECIMAL, VK_DIVIDE, VK_E, VK_F, VK_G, VK_H, VK_I, VK_J, VK_K, VK_L, VK_M,
+    VK_MULTIPLY, VK_N, VK_NUMPAD0, VK_NUMPAD1, VK_NUMPAD2, VK_NUMPAD3, VK_NUMPAD4, VK_NUMPAD5,
+    VK_NUMPAD6, VK_NUMPAD7, VK_NUMPAD8, VK_NUMPAD9, VK_O, VK_OEM_1, VK_OEM_2, VK_OEM_3, VK_OEM_4,
+    VK_OEM_5, VK_OEM_6, VK_OEM_7, VK_OEM_COMMA, VK_OEM_MINUS, VK_OEM_PERIOD, VK_OEM_PLUS, VK_P,
+    VK_Q, VK_R, VK_S, VK_SUBTRACT, VK_T, VK_U, VK_V, VK_W, VK_X, VK_Y, VK_Z,
 };
 
 pub const UNKNOWN_KEYCODE: u16 = 0;
 
-pub fn to_keycode(ch: char, keycode: u32) -> u16 {
-    let vk: VIRTUAL_KEY = VIRTUAL_KEY(keycode as u16);
-
-    match (ch, vk) {
-        // Keypad keys
-        ('0', VK_NUMPAD0) => VC_KP_0,
-        ('1', VK_NUMPAD1) => VC_KP_1,
-        ('2', VK_NUMPAD2) => VC_KP_2,
-        ('3', VK_NUMPAD3) => VC_KP_3,
-        ('4', VK_NUMPAD4) => VC_KP_4,
-        ('5', VK_NUMPAD5) => VC_KP_5,
-        ('6', VK_NUMPAD6) => VC_KP_6,
-        ('7', VK_NUMPAD7) => VC_KP_7,
-        ('8', VK_NUMPAD8) => VC_KP_8,
-        ('9', VK_NUMPAD9) => VC_KP_9,
-        ('/', VK_DIVIDE) => VC_KP_DIVIDE,
-        ('*', VK_MULTIPLY) => VC_KP_MULTIPLY,
-        ('-', VK_SUBTRACT) => VC_KP_SUBTRACT,
-        ('+', VK_ADD) => VC_KP_ADD,
-        ('.', VK_DECIMAL) => VC_KP_DECIMAL,
-
-        // Alphanumeric keys
-        ('`', _) => VC_GRAVE,
-        ('~', _) => VC_TILDE,
-        ('0', _) => VC_0,
-        (')', _) => VC_PAREN_RIGHT,
-        ('1', _) => VC_1,
-        ('!', _) => VC_EXCLAIM,
-        ('2', _) => VC_2,
-        ('@', _) => VC_AT,
-        ('3', _) => VC_3,
-        ('#', _) => VC_HASH,
-        ('4', _) => VC_4,
-        ('$', _) => VC_DOLLAR,
-        ('5', _) => VC_5,
-        ('%', _) => VC_PERCENT,
-        ('6', _) => VC_6,
-        ('^', _) => VC_CIRCUM,
-        ('7', _) => VC_7,
-        ('&', _) => VC_AMPERSAND,
-        ('8', _) => VC_8,
-        ('*', _) => VC_ASTERISK,
-        ('9', _) => VC_9,
-        ('(', _) => VC_PAREN_LEFT,
-
-        // Alphabet Keys
-        ('Q', _) => VC_Q_SHIFT,
-        ('q', _) => VC_Q,
-        ('W', _) => VC_W_SHIFT,
-        ('w', _) => VC_W,
-        ('E', _) => VC_E_SHIFT,
-        ('e', _) => VC_E,
-        ('R', _) => VC_R_SHIFT,
-        ('r', _) => VC_R,
-        ('T', _) => VC_T_SHIFT,
-        ('t', _) => VC_T,
-        ('Y', _) => VC_Y_SHIFT,
-        ('y', _) => VC_Y,
-        ('U', _) => VC_U_SHIFT,
-        ('u', _) => VC_U,
-        ('I', _) => VC_I_SHIFT,
-        ('i', _) => VC_I,
-        ('O', _) => VC_O_SHIFT,
-        ('o', _) => VC_O,
-        ('P', _) => VC_P_SHIFT,
-        ('p', _) => VC_P,
-        ('A', _) => VC_A_SHIFT,
-        ('a', _) => VC_A,
-        ('S', _) => VC_S_SHIFT,
-        ('s', _) => VC_S,
-        ('D', _) => VC_D_SHIFT,
-        ('d', _) => VC_D,
-        ('F', _) => VC_F_SHIFT,
-        ('f', _) => VC_F,
-        ('G', _) => VC_G_SHIFT,
-        ('g', _) => VC_G,
-        ('H', _) => VC_H_SHIFT,
-        ('h', _) => VC_H,
-        ('J', _) => VC_J_SHIFT,
-        ('j', _) => VC_J,
-        ('K', _) => VC_K_SHIFT,
-        ('k', _) => VC_K,
-        ('L', _) => VC_L_SHIFT,
-        ('l', _) => VC_L,
-        ('Z', _) => VC_Z_SHIFT,
-        ('z', _) => VC_Z,
-        ('X', _) => VC_X_SHIFT,
-        ('x', _) => VC_X,
-        ('C', _) => VC_C_SHIFT,
-        ('c', _) => VC_C,
-        ('V', _) => VC_V_SHIFT,
-        ('v', _) => VC_V,
-        ('B', _) => VC_B_SHIFT,
-        ('b', _) => VC_B,
-        ('N', _) => VC_N_SHIFT,
-        ('n', _) => VC_N,
-        ('M', _) => VC_M_SHIFT,
-        ('m', _) => VC_M,
-
-        ('-', _) => VC_MINUS,
-        ('_', _) => VC_UNDERSCORE,
-        ('=', _) => VC_EQUALS,
-        ('+', _) => VC_PLUS,
-
-        ('[', _) => VC_BRACKET_LEFT,
-        (']', _) => VC_BRACKET_RIGHT,
-        ('{', _) => VC_BRACE_LEFT,
-        ('}', _) => VC_BRACE_RIGHT,
-        ('\\', _) => VC_BACK_SLASH,
-        ('|', _) => VC_BAR,
-
-        (';', _) => VC_SEMICOLON,
-        (':', _) => VC_COLON,
-        ('\'', _) => VC_APOSTROPHE,
-        ('\"', _) => VC_QUOTE,
-
-        (',', _) => VC_COMMA,
-        ('<', _) => VC_LESS,
-        ('.', _) => VC_PERIOD,
-        ('>', _) => VC_GREATER,
-        ('/', _) => VC_SLASH,
-        ('?', _) => VC_QUESTION,
-
-        _ => UNKNOWN_KEYCODE,
+/// The characters typed by the keys of the main block on a US keyboard, unshifted and
+/// shifted, by scan code. 0x56 is the extra key next to the left Shift on ISO keyboards.
+const US_POSITIONS: [(u32, char, char); 48] = [
+    (0x02, '1', '!'),
+    (0x03, '2', '@'),
+    (0x04, '3', '#'),
+    (0x05, '4', '$'),
+    (0x06, '5', '%'),
+    (0x07, '6', '^'),
+    (0x08, '7', '&'),
+    (0x09, '8', '*'),
+    (0x0A, '9', '('),
+    (0x0B, '0', ')'),
+    (0x0C, '-', '_'),
+    (0x0D, '=', '+'),
+    (0x10, 'q', 'Q'),
+    (0x11, 'w', 'W'),
+    (0x12, 'e', 'E'),
+    (0x13, 'r', 'R'),
+    (0x14, 't', 'T'),
+    (0x15, 'y', 'Y'),
+    (0x16, 'u', 'U'),
+    (0x17, 'i', 'I'),
+    (0x18, 'o', 'O'),
+    (0x19, 'p', 'P'),
+    (0x1A, '[', '{'),
+    (0x1B, ']', '}'),
+    (0x1E, 'a', 'A'),
+    (0x1F, 's', 'S'),
+    (0x20, 'd', 'D'),
+    (0x21, 'f', 'F'),
+    (0x22, 'g', 'G'),
+    (0x23, 'h', 'H'),
+    (0x24, 'j', 'J'),
+    (0x25, 'k', 'K'),
+    (0x26, 'l', 'L'),
+    (0x27, ';', ':'),
+    (0x28, '\'', '"'),
+    (0x29, '`', '~'),
+    (0x2B, '\\', '|'),
+    (0x2C, 'z', 'Z'),
+    (0x2D, 'x', 'X'),
+    (0x2E, 'c', 'C'),
+    (0x2F, 'v', 'V'),
+    (0x30, 'b', 'B'),
+    (0x31, 'n', 'N'),
+    (0x32, 'm', 'M'),
+    (0x33, ',', '<'),
+    (0x34, '.', '>'),
+    (0x35, '/', '?'),
+    (0x56, '\\', '|'),
+];
+
+/// The character the key at `scancode` types on a US keyboard. Extended keys, which have
+/// KF_EXTENDED set in `scancode`, are none of these.
+pub fn position_to_char(scancode: u32, shift: bool) -> Option<char> {
+    US_POSITIONS
+        .iter()
+        .find(|(code, ..)| *code == scancode)
+        .map(|&(_, ch, shifted)| if shift { shifted } else { ch })
+}
+
+/// A character and its riti keycode.
+type Typed = (char, u16);
+
+/// The riti keycodes of the keys of the main block on a US keyboard, by virtual key,
+/// unshifted and shifted. Between them they cover every printable ASCII character but
+/// the space.
+const MAIN_KEYS: [(VIRTUAL_KEY, Typed, Typed); 47] = [
+    (VK_OEM_3, ('`', VC_GRAVE), ('~', VC_TILDE)),
+    (VK_1, ('1', VC_1), ('!', VC_EXCLAIM)),
+    (VK_2, ('2', VC_2), ('@', VC_AT)),
+    (VK_3, ('3', VC_3), ('#', VC_HASH)),
+    (VK_4, ('4', VC_4), ('$', VC_DOLLAR)),
+    (VK_5, ('5', VC_5), ('%', VC_PERCENT)),
+    (VK_6, ('6', VC_6), ('^', VC_CIRCUM)),
+    (VK_7, ('7', VC_7), ('&', VC_AMPERSAND)),
+    (VK_8, ('8', VC_8), ('*', VC_ASTERISK)),
+    (VK_9, ('9', VC_9), ('(', VC_PAREN_LEFT)),
+    (VK_0, ('0', VC_0), (')', VC_PAREN_RIGHT)),
+    (VK_OEM_MINUS, ('-', VC_MINUS), ('_', VC_UNDERSCORE)),
+    (VK_OEM_PLUS, ('=', VC_EQUALS), ('+', VC_PLUS)),
+    (VK_Q, ('q', VC_Q), ('Q', VC_Q_SHIFT)),
+    (VK_W, ('w', VC_W), ('W', VC_W_SHIFT)),
+    (VK_E, ('e', VC_E), ('E', VC_E_SHIFT)),
+    (VK_R, ('r', VC_R), ('R', VC_R_SHIFT)),
+    (VK_T, ('t', VC_T), ('T', VC_T_SHIFT)),
+    (VK_Y, ('y', VC_Y), ('Y', VC_Y_SHIFT)),
+    (VK_U, ('u', VC_U), ('U', VC_U_SHIFT)),
+    (VK_I, ('i', VC_I), ('I', VC_I_SHIFT)),
+    (VK_O, ('o', VC_O), ('O', VC_O_SHIFT)),
+    (VK_P, ('p', VC_P), ('P', VC_P_SHIFT)),
+    (VK_OEM_4, ('[', VC_BRACKET_LEFT), ('{', VC_BRACE_LEFT)),
+    (VK_OEM_6, (']', VC_BRACKET_RIGHT), ('}', VC_BRACE_RIGHT)),
+    (VK_OEM_5, ('\\', VC_BACK_SLASH), ('|', VC_BAR)),
+    (VK_A, ('a', VC_A), ('A', VC_A_SHIFT)),
+    (VK_S, ('s', VC_S), ('S', VC_S_SHIFT)),
+    (VK_D, ('d', VC_D), ('D', VC_D_SHIFT)),
+    (VK_F, ('f', VC_F), ('F', VC_F_SHIFT)),
+    (VK_G, ('g', VC_G), ('G', VC_G_SHIFT)),
+    (VK_H, ('h', VC_H), ('H', VC_H_SHIFT)),
+    (VK_J, ('j', VC_J), ('J', VC_J_SHIFT)),
+    (VK_K, ('k', VC_K), ('K', VC_K_SHIFT)),
+    (VK_L, ('l', VC_L), ('L', VC_L_SHIFT)),
+    (VK_OEM_1, (';', VC_SEMICOLON), (':', VC_COLON)),
+    (VK_OEM_7, ('\'', VC_APOSTROPHE), ('"', VC_QUOTE)),
+    (VK_Z, ('z', VC_Z), ('Z', VC_Z_SHIFT)),
+    (VK_X, ('x', VC_X), ('X', VC_X_SHIFT)),
+    (VK_C, ('c', VC_C), ('C', VC_C_SHIFT)),
+    (VK_V, ('v', VC_V), ('V', VC_V_SHIFT)),
+    (VK_B, ('b', VC_B), ('B', VC_B_SHIFT)),
+    (VK_N, ('n', VC_N), ('N', VC_N_SHIFT)),
+    (VK_M, ('m', VC_M), ('M', VC_M_SHIFT)),
+    (VK_OEM_COMMA, (',', VC_COMMA), ('<', VC_LESS)),
+    (VK_OEM_PERIOD, ('.', VC_PERIOD), ('>', VC_GREATER)),
+    (VK_OEM_2, ('/', VC_SLASH), ('?', VC_QUESTION)),
+];
+
+/// The riti keycodes of the keypad keys, which are the same whatever the layout is.
+const KEYPAD_KEYS: [(VIRTUAL_KEY, char, u16); 15] = [
+    (VK_NUMPAD0, '0', VC_KP_0),
+    (VK_NUMPAD1, '1', VC_KP_1),
+    (VK_NUMPAD2, '2', VC_KP_2),
+    (VK_NUMPAD3, '3', VC_KP_3),
+    (VK_NUMPAD4, '4', VC_KP_4),
+    (VK_NUMPAD5, '5', VC_KP_5),
+    (VK_NUMPAD6, '6', VC_KP_6),
+    (VK_NUMPAD7, '7', VC_KP_7),
+    (VK_NUMPAD8, '8', VC_KP_8),
+    (VK_NUMPAD9, '9', VC_KP_9),
+    (VK_DIVIDE, '/', VC_KP_DIVIDE),
+    (VK_MULTIPLY, '*', VC_KP_MULTIPLY),
+    (VK_SUBTRACT, '-', VC_KP_SUBTRACT),
+    (VK_ADD, '+', VC_KP_ADD),
+    (VK_DECIMAL, '.', VC_KP_DECIMAL),
+];
+
+// Every key must have a keycode of its own, or it would be typed as another, and every
+// printable ASCII character but the space must have a key.
+const _: () = {
+    let mut keycodes = [UNKNOWN_KEYCODE; 2 * MAIN_KEYS.len() + KEYPAD_KEYS.len()];
+    let mut i = 0;
+    while i < MAIN_KEYS.len() {
+        keycodes[2 * i] = MAIN_KEYS[i].1.1;
+        keycodes[2 * i + 1] = MAIN_KEYS[i].2.1;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < KEYPAD_KEYS.len() {
+        keycodes[2 * MAIN_KEYS.len() + i] = KEYPAD_KEYS[i].2;
+        i += 1;
+    }
+    let mut i = 0;
+    while i < keycodes.len() {
+        assert!(keycodes[i] != UNKNOWN_KEYCODE);
+        let mut j = i + 1;
+        while j < keycodes.len() {
+            assert!(keycodes[i] != keycodes[j], "two keys share a keycode");
+            j += 1;
+        }
+        i += 1;
+    }
+    let mut ch = b'!';
+    while ch <= b'~' {
+        let mut i = 0;
+        while i < MAIN_KEYS.len()
+            && MAIN_KEYS[i].1.0 != ch as char
+            && MAIN_KEYS[i].2.0 != ch as char
+        {
+            i += 1;
+        }
+        assert!(i < MAIN_KEYS.len(), "a printable character has no key");
+        ch += 1;
+    }
+};
+
+/// A key typing a character on a US keyboard, as riti sees it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub keycode: u16,
+    pub ch: char,
+    pub vkey: u32,
+}
+
+fn keys() -> impl Iterator<Item = Key> {
+    let main = MAIN_KEYS.iter().flat_map(|&(vkey, plain, shifted)| {
+        [plain, shifted].map(|(ch, keycode)| Key {
+            keycode,
+            ch,
+            vkey: vkey.0 as u32,
+        })
+    });
+    let keypad = KEYPAD_KEYS.iter().map(|&(vkey, ch, keycode)| Key {
+        keycode,
+        ch,
+        vkey: vkey.0 as u32,
+    });
+    main.chain(keypad)
+}
+
+/// The key that riti knows by `keycode`.
+pub fn from_keycode(keycode: u16) -> Option<Key> {
+    if keycode == UNKNOWN_KEYCODE {
+        return None;
+    }
+    keys().find(|key| key.keycode == keycode)
+}
+
+/// The riti keycode of `ch` typed by the key `vkey`. The keypad has keycodes of its own,
+/// any other key is taken for the key typing `ch` on a US keyboard.
+pub fn to_keycode(ch: char, vkey: u32) -> u16 {
+    if let Some(&(_, _, keycode)) = KEYPAD_KEYS
+        .iter()
+        .find(|&&(keypad, keypad_ch, _)| keypad.0 as u32 == vkey && keypad_ch == ch)
+    {
+        return keycode;
+    }
+    MAIN_KEYS
+        .iter()
+        .flat_map(|&(_, plain, shifted)| [plain, shifted])
+        .find(|&(key_ch, _)| key_ch == ch)
+        .map_or(UNKNOWN_KEYCODE, |(_, keycode)| keycode)
+}
+
+/// The keys to type `text` with, leaving out the characters no key types.
+pub fn type_text(text: impl IntoIterator<Item = char>) -> impl Iterator<Item = Key> {
+    text.into_iter()
+        .filter_map(|ch| from_keycode(to_keycode(ch, 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for ch in '!'..='~' {
+            let keycode = to_keycode(ch, 0);
+            let key = from_keycode(keycode).unwrap();
+            assert_eq!(key.ch, ch);
+            assert_eq!(to_keycode(key.ch, key.vkey), keycode, "{ch}");
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        assert_eq!(to_keycode(' ', 0x20), UNKNOWN_KEYCODE);
+        assert_eq!(to_keycode('অ', 0x41), UNKNOWN_KEYCODE);
+        assert_eq!(from_keycode(UNKNOWN_KEYCODE), None);
+    }
+
+    #[test]
+    fn keypad() {
+        let multiply = VK_MULTIPLY.0 as u32;
+        assert_eq!(to_keycode('*', multiply), VC_KP_MULTIPLY);
+        assert_eq!(
+            from_keycode(VC_KP_MULTIPLY),
+            Some(Key {
+                keycode: VC_KP_MULTIPLY,
+                ch: '*',
+                vkey: multiply,
+            })
+        );
+        // typed elsewhere, it's the one over 8
+        assert_eq!(to_keycode('*', VK_8.0 as u32), VC_ASTERISK);
+        assert_eq!(to_keycode('*', 0), VC_ASTERISK);
+        assert_eq!(to_keycode('5', VK_NUMPAD5.0 as u32), VC_KP_5);
+        assert_eq!(to_keycode('5', VK_5.0 as u32), VC_5);
+    }
+
+    #[test]
+    fn typing() {
+        let keys: Vec<(char, u32)> = type_text("Ab ক1?".chars())
+            .map(|key| (key.ch, key.vkey))
+            .collect();
+        // the space and the Bangla letter are typed by no key
+        let expected = [('A', VK_A), ('b', VK_B), ('1', VK_1), ('?', VK_OEM_2)]
+            .map(|(ch, vkey)| (ch, vkey.0 as u32));
+        assert_eq!(keys, expected);
+        assert_eq!(type_text("".chars()).count(), 0);
+    }
+
+    #[test]
+    fn positions() {
+        assert_eq!(position_to_char(0x1E, false), Some('a'));
+        assert_eq!(position_to_char(0x1E, true), Some('A'));
+        assert_eq!(position_to_char(0x35, false), Some('/'));
+        // the keypad's /, which is extended
+        assert_eq!(position_to_char(0x135, false), None);
+        assert_eq!(position_to_char(0x01, false), None);
     }
 }
@@ -1,26 +1,39 @@
 mod composition;
 pub mod display_attribute_provider;
+mod document;
+mod edit_queue;
 mod edit_session;
+mod focus;
+mod input_scope;
 pub(super) mod key_event_sink;
+mod key_translator;
 pub mod keycode;
 mod langbar_item;
+mod preserved_keys;
+mod reconversion;
+mod session;
 pub mod text_input_processor;
 mod thread_mgr_event_sink;
+mod transliteration;
 
-use std::time::{Duration, Instant};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
 use log::{debug, error, warn};
 use log_derive::logfn;
 use parking_lot::{RwLock, RwLockWriteGuard};
-use riti::{context::RitiContext, suggestion::Suggestion};
+use riti::context::RitiContext;
 use windows::{
     Win32::{
         Foundation::E_FAIL,
         UI::{
             TextServices::{
-                HKL, ITfComposition, ITfCompositionSink, ITfContext, ITfDisplayAttributeProvider,
-                ITfKeyEventSink, ITfLangBarItem, ITfTextInputProcessor, ITfTextInputProcessorEx,
-                ITfThreadMgr, ITfThreadMgrEventSink,
+                HKL, ITfCompositionSink, ITfContext, ITfDisplayAttributeProvider,
+                ITfFunctionProvider, ITfKeyEventSink, ITfLangBarItem, ITfRange,
+                ITfTextInputProcessor, ITfTextInputProcessorEx, ITfThreadMgr,
+                ITfThreadMgrEventSink,
             },
             WindowsAndMessaging::HICON,
         },
@@ -28,7 +41,18 @@ use windows::{
     core::{AsImpl, Interface, Result, VARIANT, implement},
 };
 
-use crate::{conf::{Settings, set_riti_config}, global::IME_KEYBOARD_US, ui::candidate_list::CandidateList};
+use self::{
+    display_attribute_provider::DisplayAttribute, edit_queue::EditQueue,
+    preserved_keys::PreservedKeys, session::Session,
+};
+use crate::{
+    conf::{EnterAction, FocusAction, Settings, set_riti_config},
+    extend::ResultExt,
+    global::hkl_or_us,
+    keymap::Keymap,
+    tap::TapTracker,
+    ui::candidate_list::CandidateList,
+};
 
 //----------------------------------------------------------------------------
 //
@@ -49,27 +73,63 @@ use crate::{conf::{Settings, set_riti_config}, global::IME_KEYBOARD_US, ui::cand
     ITfKeyEventSink,
     ITfCompositionSink,
     ITfLangBarItem,
-    ITfDisplayAttributeProvider
+    ITfDisplayAttributeProvider,
+    ITfFunctionProvider
 )]
 pub struct TextService {
     inner: RwLock<TextServiceInner>,
 }
 struct TextServiceInner {
-    riti: RitiContext,
     // Some basic info about the clinet (the program where user is typing)
     tid: u32,
     thread_mgr: Option<ITfThreadMgr>,
     context: Option<ITfContext>,
+    // The engine, holding the input of the session of the context above
+    riti: RitiContext,
+    // The input session of the context above, and those of the other contexts
+    session: Session,
+    sessions: Vec<(ITfContext, Session)>,
+    // Secure fields and input scopes
+    secure_mode: bool,
+    secure: bool,
+    scoped_context: Option<ITfContext>,
+    // The mode chosen by the user and the one forced by the focused field's input scope
+    mode: Mode,
+    scope_mode: Option<Mode>,
+    // Disabled for the host application
+    disabled: bool,
+    // What Enter and Shift+Enter do while composing, the keys bound to other actions and
+    // the modifiers being tapped
+    keymap: Keymap,
+    taps: TapTracker,
+    enter_action: EnterAction,
+    shift_enter_action: EnterAction,
+    // What a focus change does to the composition
+    focus_action: FocusAction,
     // ThreadMrgEventSink
     cookie: Option<u32>,
-    // KeyEventSink
+    // KeyEventSink, the keys it preserves, and whether keys are mapped by position for a
+    // fixed layout
     hkl: HKL,
-    // Composition
-    composition: Option<ITfComposition>,
-    suggestions: Option<Suggestion>,
-    preedit: String,
-    // display attribute provider
-    display_attribute: Option<VARIANT>,
+    preserved_keys: PreservedKeys,
+    positional_keys: bool,
+    // Whether only the right Alt types the AltGr layer of the layout
+    right_alt_only: bool,
+    // Keypad digits type Bangla numerals rather than going to the application
+    number_pad: bool,
+    // The edits to the document waiting for the application
+    edits: EditQueue,
+    // Committed words and the roman input they were typed with, for reconversion
+    history: VecDeque<(String, String)>,
+    // Transliteration of the selection waiting to be confirmed, and whether to wait at all
+    preview: Option<(ITfRange, String)>,
+    transliteration_preview: bool,
+    // Emoji layer
+    emoji_enabled: bool,
+    emoji_trigger: String,
+    // display attribute provider, the atoms of the attributes and their look
+    display_attributes: Vec<(DisplayAttribute, VARIANT)>,
+    highlight_target: bool,
     // UI
     candidate_list: Option<CandidateList>,
     icon: HICON,
@@ -77,28 +137,53 @@ struct TextServiceInner {
     interface: Option<ITfTextInputProcessor>,
 }
 
+/// Keys either go to riti to type Bangla or straight to the application as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Bangla,
+    English,
+}
+
 impl TextService {
     #[logfn(err = "Error")]
     pub fn create() -> Result<ITfTextInputProcessor> {
-        let Ok(settings) = Settings::load_or_create() else {
-            panic!("Failed to load settings from registry.");
-        };
-
+        let settings = Settings::load_or_create()?;
         let config = set_riti_config(&settings);
 
         let inner = TextServiceInner {
-            riti: RitiContext::new_with_config(&config),
             tid: 0,
             thread_mgr: None,
             context: None,
-            hkl: IME_KEYBOARD_US,
+            riti: RitiContext::new_with_config(&config),
+            session: Session::new(),
+            sessions: Vec::new(),
+            secure_mode: false,
+            secure: false,
+            scoped_context: None,
+            mode: Mode::Bangla,
+            scope_mode: None,
+            disabled: false,
+            keymap: settings.get_keymap(),
+            taps: TapTracker::default(),
+            enter_action: settings.get_enter_key_action(),
+            shift_enter_action: settings.get_shift_enter_key_action(),
+            focus_action: settings.get_focus_change_action(),
+            hkl: hkl_or_us(),
+            preserved_keys: PreservedKeys::default(),
+            positional_keys: settings.get_positional_keys_fixed(),
+            right_alt_only: settings.get_right_alt_only_fixed(),
+            number_pad: settings.get_number_pad_fixed() && settings.is_fixed_layout(),
             cookie: None,
-            composition: None,
-            suggestions: None,
-            preedit: String::with_capacity(32),
+            edits: EditQueue::default(),
+            history: VecDeque::new(),
+            preview: None,
+            transliteration_preview: settings.get_transliteration_preview(),
+            emoji_enabled: settings.get_emoji_enabled(),
+            emoji_trigger: settings.get_emoji_trigger(),
             icon: HICON::default(),
             candidate_list: None,
-            display_attribute: None,
+            display_attributes: Vec::new(),
+            highlight_target: settings.get_highlight_target(),
             interface: None,
         };
         let text_service = TextService {
@@ -162,10 +247,21 @@ impl TextServiceInner {
         })
     }
 
+    /// The mode in effect: the input scope's if it has an opinion, otherwise the user's.
+    fn mode(&self) -> Mode {
+        self.scope_mode.unwrap_or(self.mode)
+    }
+
     fn candidate_list(&self) -> Result<&CandidateList> {
         self.candidate_list.as_ref().ok_or(E_FAIL.into())
     }
 
+    fn is_candidate_list_vertical(&self) -> bool {
+        self.candidate_list
+            .as_ref()
+            .is_some_and(CandidateList::is_vertical)
+    }
+
     fn create_candidate_list(&mut self) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
@@ -190,12 +286,30 @@ impl TextServiceInner {
     }
 
     fn update_engine(&mut self) {
-        let Ok(settings) = Settings::load_or_create() else {
-            panic!("Failed to load settings from registry.");
+        let Ok(settings) = Settings::load_or_create().log_err() else {
+            // keep going with what was read last time
+            return;
         };
 
-        let config = set_riti_config(&settings);
+        let mut config = set_riti_config(&settings);
+        self.session.hints.configure(&mut config);
         self.riti.update_engine(&config);
+        self.emoji_enabled = settings.get_emoji_enabled();
+        self.emoji_trigger = settings.get_emoji_trigger();
+        self.enter_action = settings.get_enter_key_action();
+        self.shift_enter_action = settings.get_shift_enter_key_action();
+        self.focus_action = settings.get_focus_change_action();
+        self.highlight_target = settings.get_highlight_target();
+        self.positional_keys = settings.get_positional_keys_fixed();
+        self.right_alt_only = settings.get_right_alt_only_fixed();
+        self.number_pad = settings.get_number_pad_fixed() && settings.is_fixed_layout();
+        self.transliteration_preview = settings.get_transliteration_preview();
+        let keymap = settings.get_keymap();
+        if keymap != self.keymap {
+            debug!("The key bindings have changed.");
+            self.keymap = keymap;
+            self.rebind_preserved_keys(&settings);
+        }
 
         if let Some(candidate_list) = &self.candidate_list {
             candidate_list.set_vertical(!settings.get_candidate_win_horizontal());
@@ -0,0 +1,282 @@
+use std::ffi::OsString;
+
+use log::{debug, trace};
+use riti::context::{MODIFIER_ALT_GR, MODIFIER_SHIFT, RitiContext};
+use windows::{
+    Win32::UI::{
+        Input::KeyboardAndMouse::{MAPVK_VK_TO_VSC, MapVirtualKeyExW, ToUnicodeEx, VK_SHIFT},
+        TextServices::{
+            HKL, ITfKeystrokeMgr, TF_MOD_ALT, TF_MOD_CONTROL, TF_MOD_LCONTROL, TF_MOD_RALT,
+            TF_MOD_SHIFT, TF_PRESERVEDKEY,
+        },
+    },
+    core::{GUID, Interface, Result},
+};
+
+use super::TextServiceInner;
+use crate::{
+    conf::{Settings, set_riti_config},
+    extend::{CharExt, OsStrExt2, ResultExt},
+    global::hkl_or_us,
+    keymap::{Action, Chord},
+    tsf::keycode::{UNKNOWN_KEYCODE, position_to_char, to_keycode},
+};
+
+//----------------------------------------------------------------------------
+//
+//  Preserved keys: Ctrl+Alt combinations are delivered via WM_SYSKEYDOWN
+//  which TSF does not forward to the key event sink. The AltGr keys of the
+//  layout are registered as preserved keys so they arrive via OnPreservedKey
+//  instead. Hotkeys of the input method's own functions are preserved keys
+//  as well. Only the keys the layout types with are taken from applications,
+//  so they are picked again whenever the layout changes. AltGr is the right
+//  Alt, which Windows sends along with a left Ctrl. With `RightAltOnly` set
+//  nothing else counts, leaving Ctrl+Alt to the applications' shortcuts.
+//
+//----------------------------------------------------------------------------
+
+/// What a preserved key stands for.
+#[derive(Debug, Clone, Copy)]
+pub enum PreservedKey {
+    Hotkey(Action),
+    /// AltGr(+Shift) over a key, or Ctrl+Alt(+Shift) unless only the right Alt is AltGr.
+    AltGr {
+        vkey: u32,
+        shift: bool,
+    },
+}
+
+/// The keys preserved with the keystroke manager and the layout (along with whether only the
+/// right Alt is AltGr and the keyboard layout of Windows) they were picked for, None if
+/// nothing is registered.
+#[derive(Default)]
+pub struct PreservedKeys {
+    keys: Vec<(GUID, TF_PRESERVEDKEY, PreservedKey)>,
+    layout: Option<(String, bool, HKL)>,
+}
+
+impl PreservedKeys {
+    pub fn lookup(&self, guid: &GUID) -> Option<PreservedKey> {
+        self.keys
+            .iter()
+            .find(|(id, ..)| id == guid)
+            .map(|&(.., key)| key)
+    }
+}
+
+/// The input method's own functions, the hotkeys of which are bound in the keymap.
+/// Each chord is preserved with a GUID of its own, see Settings::get_preserved_key_guid.
+const HOTKEYS: [(Action, &str); 5] = [
+    // See tsf/reconversion.rs
+    (Action::Reconversion, "Reconversion"),
+    // See tsf/transliteration.rs
+    (Action::Transliteration, "Transliteration"),
+    // See TextServiceInner::undo_commit
+    (Action::UndoCommit, "Undo commit"),
+    (Action::ToggleMode, "Toggle mode"),
+    (Action::SwitchLayout, "Switch layout"),
+];
+
+/// Virtual key codes that may type something with AltGr: A-Z, 0-9 and the OEM keys
+/// ;: =+ ,< -_ .> /? `~ [{ \| ]} '"
+const ALTGR_VKEYS: &[u32] = &[
+    0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x4B, 0x4C, 0x4D, 0x4E, 0x4F, 0x50,
+    0x51, 0x52, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x30, 0x31, 0x32, 0x33, 0x34, 0x35,
+    0x36, 0x37, 0x38, 0x39, 0xBA, 0xBB, 0xBC, 0xBD, 0xBE, 0xBF, 0xC0, 0xDB, 0xDC, 0xDD, 0xDE,
+];
+
+impl TextServiceInner {
+    /// Preserve the hotkeys bound in the keymap and the AltGr keys of the layout.
+    pub fn register_preserved_keys(&mut self, settings: &Settings) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        let keystroke_mgr = self.thread_mgr()?.cast::<ITfKeystrokeMgr>()?;
+        let mut wanted = Vec::new();
+        for &(chord, action) in self.keymap.bindings() {
+            // taps are seen by the key event sink
+            if chord.is_tap() {
+                continue;
+            }
+            if let Some(&(_, desc)) = HOTKEYS.iter().find(|(hotkey, _)| *hotkey == action) {
+                let key = PreservedKey::Hotkey(action);
+                wanted.push((
+                    chord.to_string(),
+                    preserved_key(chord),
+                    key,
+                    desc.to_string(),
+                ));
+            }
+        }
+        let right_alt_only = settings.get_right_alt_only_fixed();
+        for (vkey, shift) in self.altgr_keys(settings) {
+            let mut modifiers = if right_alt_only {
+                TF_MOD_RALT | TF_MOD_LCONTROL
+            } else {
+                TF_MOD_CONTROL | TF_MOD_ALT
+            };
+            if shift {
+                modifiers |= TF_MOD_SHIFT;
+            }
+            let prekey = TF_PRESERVEDKEY {
+                uVKey: vkey,
+                uModifiers: modifiers,
+            };
+            let key = Chord {
+                key: vkey,
+                ctrl: false,
+                shift: false,
+                alt: false,
+            };
+            let desc = format!("{}AltGr+{key}", if shift { "Shift+" } else { "" });
+            wanted.push((
+                desc.clone(),
+                prekey,
+                PreservedKey::AltGr { vkey, shift },
+                desc,
+            ));
+        }
+
+        for (name, prekey, key, desc) in wanted {
+            let Ok(guid) = settings.get_preserved_key_guid(&name).log_err() else {
+                continue;
+            };
+            let wide = OsString::from(&desc).to_wchars();
+            match unsafe { keystroke_mgr.PreserveKey(self.tid, &guid, &prekey, &wide) } {
+                Ok(()) => self.preserved_keys.keys.push((guid, prekey, key)),
+                // most likely taken by a hotkey already
+                Err(e) => trace!("Failed to preserve {desc} for {:?}: {}", key, e),
+            }
+        }
+        self.preserved_keys.layout = Some((settings.get_layout_path(), right_alt_only, self.hkl));
+        debug!("Preserved {} keys.", self.preserved_keys.keys.len());
+        Ok(())
+    }
+
+    pub fn unregister_preserved_keys(&mut self) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        let keystroke_mgr = self.thread_mgr()?.cast::<ITfKeystrokeMgr>()?;
+        for (guid, prekey, _) in self.preserved_keys.keys.drain(..) {
+            let _ = unsafe { keystroke_mgr.UnpreserveKey(&guid, &prekey) };
+        }
+        self.preserved_keys.layout = None;
+        Ok(())
+    }
+
+    /// Pick the keys to preserve again if the layout has changed since they were registered.
+    /// The keyboard layout of Windows is read again along the way.
+    pub fn refresh_preserved_keys(&mut self, settings: &Settings) {
+        let hkl = hkl_or_us();
+        if hkl != self.hkl {
+            debug!("Using keyboard layout {:08X}.", hkl.0);
+            self.hkl = hkl;
+        }
+        let layout = (
+            settings.get_layout_path(),
+            settings.get_right_alt_only_fixed(),
+            self.hkl,
+        );
+        if self
+            .preserved_keys
+            .layout
+            .as_ref()
+            .is_none_or(|registered| *registered == layout)
+        {
+            return;
+        }
+        debug!("The layout is now {layout:?}, preserving its keys.");
+        let _ = self
+            .unregister_preserved_keys()
+            .and_then(|()| self.register_preserved_keys(settings))
+            .log_err();
+    }
+
+    /// Preserve the keys again for the bindings in the keymap, which the hotkeys are
+    /// preserved by. Nothing to do if no keys are preserved at all.
+    pub fn rebind_preserved_keys(&mut self, settings: &Settings) {
+        if self.preserved_keys.layout.is_none() {
+            return;
+        }
+        debug!("Preserving the keys of the new bindings.");
+        let _ = self
+            .unregister_preserved_keys()
+            .and_then(|()| self.register_preserved_keys(settings))
+            .log_err();
+    }
+
+    /// The keys the layout types something with when AltGr is held, with Shift or without.
+    /// riti is asked key by key, in an engine of its own.
+    fn altgr_keys(&self, settings: &Settings) -> Vec<(u32, bool)> {
+        // phonetic layouts have no use for AltGr
+        if !settings.is_fixed_layout() {
+            return Vec::new();
+        }
+        let positional = settings.get_positional_keys_fixed();
+        let mut riti = RitiContext::new_with_config(&set_riti_config(settings));
+        let mut keys = Vec::new();
+        for &vkey in ALTGR_VKEYS {
+            for shift in [false, true] {
+                let Some(ch) = self.base_char(vkey, shift, positional) else {
+                    continue;
+                };
+                let key = to_keycode(ch, vkey);
+                if key == UNKNOWN_KEYCODE {
+                    continue;
+                }
+                let modifier = if shift {
+                    MODIFIER_SHIFT ^ MODIFIER_ALT_GR
+                } else {
+                    MODIFIER_ALT_GR
+                };
+                riti.finish_input_session();
+                let plain = riti.get_suggestion_for_key(key, 0, 0);
+                riti.finish_input_session();
+                let typed = riti.get_suggestion_for_key(key, modifier, 0);
+                // a key that types the same with AltGr has no AltGr mapping of its own
+                if !typed.is_empty()
+                    && (plain.is_empty()
+                        || typed.get_pre_edit_text(0) != plain.get_pre_edit_text(0))
+                {
+                    keys.push((vkey, shift));
+                }
+            }
+        }
+        riti.finish_input_session();
+        keys
+    }
+
+    /// What the key types with Shift or without, the way translate_key reads it.
+    fn base_char(&self, vkey: u32, shift: bool, positional: bool) -> Option<char> {
+        let scancode = unsafe { MapVirtualKeyExW(vkey, MAPVK_VK_TO_VSC, self.hkl) };
+        if positional {
+            return position_to_char(scancode, shift);
+        }
+        let mut keyboard_state = [0; 256];
+        if shift {
+            keyboard_state[VK_SHIFT.0 as usize] = 0x80;
+        }
+        let mut buf = [0; 8];
+        let ret = unsafe { ToUnicodeEx(vkey, scancode, &keyboard_state, &mut buf, 0, self.hkl) };
+        if ret <= 0 {
+            return None;
+        }
+        char::try_from_utf16(buf[0]).ok()
+    }
+}
+
+fn preserved_key(chord: Chord) -> TF_PRESERVEDKEY {
+    let mut modifiers = 0;
+    if chord.ctrl {
+        modifiers |= TF_MOD_CONTROL;
+    }
+    if chord.shift {
+        modifiers |= TF_MOD_SHIFT;
+    }
+    if chord.alt {
+        modifiers |= TF_MOD_ALT;
+    }
+    TF_PRESERVEDKEY {
+        uVKey: chord.key,
+        uModifiers: modifiers,
+    }
+}
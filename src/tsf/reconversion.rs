@@ -0,0 +1,316 @@
+use std::{cell::Cell, ffi::OsString};
+
+use log::{debug, trace};
+use windows::{
+    Win32::{
+        Foundation::{BOOL, E_FAIL, E_INVALIDARG, E_NOINTERFACE, S_FALSE},
+        UI::TextServices::{
+            CAND_FINALIZED, IEnumTfCandidates, IEnumTfCandidates_Impl, ITfCandidateList,
+            ITfCandidateList_Impl, ITfCandidateString, ITfCandidateString_Impl, ITfContext,
+            ITfFnReconversion, ITfFnReconversion_Impl, ITfFunction_Impl, ITfFunctionProvider_Impl,
+            ITfRange, ITfTextInputProcessor, TfCandidateResult,
+        },
+    },
+    core::{AsImpl, BSTR, GUID, IUnknown, Interface, Result, implement},
+};
+
+use super::{TextService, TextServiceInner, edit_queue::Target, edit_session};
+use crate::{
+    extend::OsStrExt2,
+    global::{IME_ID, IME_NAME},
+    romanize::romanize,
+};
+
+/// How many committed words are remembered along with the roman input they were typed with.
+const HISTORY_LEN: usize = 64;
+
+//----------------------------------------------------------------------------
+//
+//  Reconversion reopens committed text as a composition so that another
+//  candidate can be picked. It's either started by the hotkey or by the
+//  application through ITfFnReconversion, which the text service hands out
+//  as a function provider.
+//
+//  riti is fed the roman input the word was typed with if it's remembered,
+//  or a romanization of the word otherwise.
+//
+//----------------------------------------------------------------------------
+
+impl TextServiceInner {
+    /// Remember the roman input in the preedit as the one `bangla` was typed with.
+    pub fn remember_roman(&mut self, bangla: &str) {
+        self.history.retain(|(word, _)| word != bangla);
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history
+            .push_back((bangla.to_string(), self.session.preedit.clone()));
+    }
+
+    fn roman_of(&self, bangla: &str) -> Vec<char> {
+        match self.history.iter().rfind(|(word, _)| word == bangla) {
+            Some((_, roman)) => roman.chars().collect(),
+            None => romanize(bangla).chars().collect(),
+        }
+    }
+
+    /// Reopen the committed word at `range`, or at the selection if None, as a composition.
+    /// Returns false if there's nothing to reconvert.
+    pub fn reconvert(&mut self, context: &ITfContext, range: Option<&ITfRange>) -> Result<bool> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        self.switch_context(context)?;
+        if self.session.composition.is_some() {
+            return Ok(false);
+        }
+        let Some((range, bangla)) =
+            edit_session::get_word_range(&self.edits, self.tid, context, range)?
+        else {
+            return Ok(false);
+        };
+        self.update_engine();
+        let roman = self.roman_of(&bangla);
+        let Some(suggestion) = self.type_roman(&roman) else {
+            debug!("Nothing to reconvert {bangla} into.");
+            self.riti.finish_input_session();
+            return Ok(false);
+        };
+        trace!("Reconverting {bangla}.");
+        self.start_composition(Some(&range))?;
+        self.apply_suggestion(suggestion)?;
+        Ok(true)
+    }
+
+    /// What `bangla` could have been instead, without touching the document.
+    fn alternatives(&mut self, bangla: &str) -> Vec<String> {
+        if self.session.composition.is_some() {
+            return Vec::new();
+        }
+        let roman = self.roman_of(bangla);
+        let alternatives = match self.type_roman(&roman) {
+            Some(suggestion) if suggestion.is_lonely() => vec![suggestion.get_pre_edit_text(0)],
+            Some(suggestion) => suggestion.get_suggestions().to_vec(),
+            None => Vec::new(),
+        };
+        self.riti.finish_input_session();
+        alternatives
+    }
+}
+
+//----------------------------------------------------------------------------
+//
+//  The function provider and ITfFnReconversion.
+//
+//----------------------------------------------------------------------------
+
+#[allow(non_snake_case)]
+impl ITfFunctionProvider_Impl for TextService {
+    fn GetType(&self) -> Result<GUID> {
+        Ok(IME_ID)
+    }
+
+    fn GetDescription(&self) -> Result<BSTR> {
+        Ok(BSTR::from(IME_NAME))
+    }
+
+    fn GetFunction(&self, rguid: *const GUID, riid: *const GUID) -> Result<IUnknown> {
+        let (rguid, riid) = unsafe { (rguid.as_ref(), riid.as_ref()) };
+        if rguid != Some(&GUID::zeroed()) || riid != Some(&ITfFnReconversion::IID) {
+            return Err(E_NOINTERFACE.into());
+        }
+        let service = self.write()?.interface::<ITfTextInputProcessor>()?;
+        ITfFnReconversion::from(Reconversion { service }).cast()
+    }
+}
+
+#[implement(ITfFnReconversion)]
+struct Reconversion {
+    service: ITfTextInputProcessor,
+}
+
+impl Reconversion {
+    fn service(&self) -> &TextService {
+        unsafe { self.service.as_impl() }
+    }
+}
+
+#[allow(non_snake_case)]
+impl ITfFunction_Impl for Reconversion {
+    fn GetDisplayName(&self) -> Result<BSTR> {
+        Ok(BSTR::from("Reconversion"))
+    }
+}
+
+#[allow(non_snake_case)]
+impl ITfFnReconversion_Impl for Reconversion {
+    fn QueryRange(
+        &self,
+        range: Option<&ITfRange>,
+        new_range: *mut Option<ITfRange>,
+        convertable: *mut BOOL,
+    ) -> Result<()> {
+        trace!("QueryRange");
+        let range = range.ok_or(E_INVALIDARG)?;
+        let context = unsafe { range.GetContext()? };
+        let inner = self.service().write()?;
+        let word = edit_session::get_word_range(&inner.edits, inner.tid, &context, Some(range))?;
+        unsafe {
+            *convertable = word.is_some().into();
+            *new_range = word.map(|(range, _)| range);
+        }
+        Ok(())
+    }
+
+    fn GetReconversion(&self, range: Option<&ITfRange>) -> Result<ITfCandidateList> {
+        trace!("GetReconversion");
+        let range = range.ok_or(E_INVALIDARG)?;
+        let context = unsafe { range.GetContext()? };
+        let mut inner = self.service().write()?;
+        let (range, bangla) =
+            edit_session::get_word_range(&inner.edits, inner.tid, &context, Some(range))?
+                .ok_or(E_FAIL)?;
+        let candidates = inner.alternatives(&bangla);
+        Ok(CandidateList {
+            service: self.service.clone(),
+            range,
+            candidates,
+        }
+        .into())
+    }
+
+    fn Reconvert(&self, range: Option<&ITfRange>) -> Result<()> {
+        trace!("Reconvert");
+        let range = range.ok_or(E_INVALIDARG)?;
+        let context = unsafe { range.GetContext()? };
+        if self.service().write()?.reconvert(&context, Some(range))? {
+            Ok(())
+        } else {
+            Err(E_FAIL.into())
+        }
+    }
+}
+
+/// Alternatives for a word, handed to applications that show their own candidate UI.
+#[implement(ITfCandidateList)]
+struct CandidateList {
+    service: ITfTextInputProcessor,
+    range: ITfRange,
+    candidates: Vec<String>,
+}
+
+#[allow(non_snake_case)]
+impl ITfCandidateList_Impl for CandidateList {
+    fn EnumCandidates(&self) -> Result<IEnumTfCandidates> {
+        Ok(CandidateEnum {
+            candidates: self.candidates.clone(),
+            cursor: Cell::new(0),
+        }
+        .into())
+    }
+
+    fn GetCandidate(&self, index: u32) -> Result<ITfCandidateString> {
+        let text = self.candidates.get(index as usize).ok_or(E_INVALIDARG)?;
+        Ok(CandidateString {
+            index,
+            text: text.clone(),
+        }
+        .into())
+    }
+
+    fn GetCandidateNum(&self) -> Result<u32> {
+        Ok(self.candidates.len() as u32)
+    }
+
+    fn SetResult(&self, index: u32, result: TfCandidateResult) -> Result<()> {
+        if result != CAND_FINALIZED {
+            return Ok(());
+        }
+        let text = self.candidates.get(index as usize).ok_or(E_INVALIDARG)?;
+        let text = OsString::from(text).to_wchars();
+        let len = text.len();
+        let service: &TextService = unsafe { self.service.as_impl() };
+        let inner = service.write()?;
+        let (context, range) = unsafe { (self.range.GetContext()?, self.range.Clone()?) };
+        inner.edits.set_text(
+            inner.tid,
+            &context,
+            Target::Range(range),
+            text,
+            len,
+            Vec::new(),
+        )
+    }
+}
+
+#[implement(IEnumTfCandidates)]
+struct CandidateEnum {
+    candidates: Vec<String>,
+    cursor: Cell<usize>,
+}
+
+#[allow(non_snake_case)]
+impl IEnumTfCandidates_Impl for CandidateEnum {
+    fn Clone(&self) -> Result<IEnumTfCandidates> {
+        Ok(CandidateEnum {
+            candidates: self.candidates.clone(),
+            cursor: self.cursor.clone(),
+        }
+        .into())
+    }
+
+    fn Next(
+        &self,
+        count: u32,
+        candidates: *mut Option<ITfCandidateString>,
+        fetched: *mut u32,
+    ) -> Result<()> {
+        let start = self.cursor.get();
+        let end = self.candidates.len().min(start + count as usize);
+        for (offset, index) in (start..end).enumerate() {
+            let candidate = CandidateString {
+                index: index as u32,
+                text: self.candidates[index].clone(),
+            };
+            unsafe { *candidates.add(offset) = Some(candidate.into()) };
+        }
+        self.cursor.set(end);
+        if !fetched.is_null() {
+            unsafe { *fetched = (end - start) as u32 };
+        }
+        if end - start < count as usize {
+            return Err(S_FALSE.into());
+        }
+        Ok(())
+    }
+
+    fn Reset(&self) -> Result<()> {
+        self.cursor.set(0);
+        Ok(())
+    }
+
+    fn Skip(&self, count: u32) -> Result<()> {
+        let cursor = self
+            .candidates
+            .len()
+            .min(self.cursor.get() + count as usize);
+        self.cursor.set(cursor);
+        Ok(())
+    }
+}
+
+#[implement(ITfCandidateString)]
+struct CandidateString {
+    index: u32,
+    text: String,
+}
+
+#[allow(non_snake_case)]
+impl ITfCandidateString_Impl for CandidateString {
+    fn GetString(&self) -> Result<BSTR> {
+        Ok(BSTR::from(self.text.as_str()))
+    }
+
+    fn GetIndex(&self) -> Result<u32> {
+        Ok(self.index)
+    }
+}
@@ -0,0 +1,147 @@
+use std::mem;
+
+use log::{debug, trace};
+use riti::suggestion::Suggestion;
+use windows::{
+    Win32::UI::TextServices::{ITfContext, ITfDocumentMgr},
+    core::Result,
+};
+
+use super::{TextServiceInner, composition::LastCommit, edit_queue::Composition, focus::Suspended};
+use crate::surrounding::Hints;
+
+//----------------------------------------------------------------------------
+//
+//  Input sessions. Every context (i.e. text field) gets a composition of
+//  its own so that a word left halfway in one field doesn't get mixed with
+//  the keys typed into another. There's only one riti engine; it holds the
+//  input of the current session and is retyped from the preedit of the
+//  next one on a switch. Sessions are opened as contexts are first typed
+//  into and dropped as they are popped. The session of the context being
+//  typed into is kept at hand in `TextServiceInner::session` and the others
+//  are parked, composition and all, until their context is focused or typed
+//  into again.
+//
+//----------------------------------------------------------------------------
+
+/// What's being typed into a context.
+pub struct Session {
+    pub composition: Option<Composition>,
+    pub suggestions: Option<Suggestion>,
+    pub preedit: String,
+    // Position of the caret within the roman input, None for the end
+    pub caret: Option<usize>,
+    // Candidates on screen, of which the first `riti_candidates` come from riti
+    pub candidates: Vec<String>,
+    pub riti_candidates: usize,
+    // What the text before the caret tells about the word being typed
+    pub hints: Hints,
+    // The commit Backspace or the undo hotkey would take back, cleared by any other key
+    pub last_commit: Option<LastCommit>,
+    // The composition put aside while the focus is away
+    pub suspended: Option<Suspended>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            composition: None,
+            suggestions: None,
+            preedit: String::with_capacity(32),
+            caret: None,
+            candidates: Vec::new(),
+            riti_candidates: 0,
+            hints: Hints::default(),
+            last_commit: None,
+            suspended: None,
+        }
+    }
+}
+
+impl TextServiceInner {
+    /// Drop the session of a context that was popped.
+    pub fn close_session(&mut self, context: &ITfContext) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        self.edits.drop_pending(context);
+        self.sessions.retain(|(parked, _)| parked != context);
+        if self.context.as_ref() == Some(context) {
+            self.close_current_session()?;
+        }
+        Ok(())
+    }
+
+    /// Drop the sessions of every context of a document manager about to go.
+    pub fn close_sessions_of(&mut self, document_mgr: &ITfDocumentMgr) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        let belongs = |context: &ITfContext| {
+            unsafe { context.GetDocumentMgr() }.is_ok_and(|owner| &owner == document_mgr)
+        };
+        self.sessions.retain(|(parked, _)| !belongs(parked));
+        if self.context.as_ref().is_some_and(belongs) {
+            self.close_current_session()?;
+        }
+        Ok(())
+    }
+
+    /// Make the session of `context` the current one, parking the one it replaces.
+    pub fn switch_context(&mut self, context: &ITfContext) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        if self.context.as_ref() == Some(context) {
+            return Ok(());
+        }
+        let session = match self
+            .sessions
+            .iter()
+            .position(|(parked, _)| parked == context)
+        {
+            Some(index) => self.sessions.swap_remove(index).1,
+            None => {
+                trace!("Opening a session.");
+                Session::new()
+            }
+        };
+        debug!("Switching to the session of another context.");
+        let parked = mem::replace(&mut self.session, session);
+        if let Some(previous) = self.context.replace(context.clone()) {
+            self.sessions.push((previous, parked));
+        }
+        // the candidates on screen belong to the other context
+        if self.session.composition.is_some() {
+            let roman: Vec<char> = self.session.preedit.chars().collect();
+            let _ = self.type_roman(&roman);
+            self.update_candidate_list()
+        } else {
+            self.riti.finish_input_session();
+            self.candidate_list()?.hide();
+            Ok(())
+        }
+    }
+
+    /// Park the current session as it is, for the focus is moving to another document.
+    pub fn park_session(&mut self) -> Result<()> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        let Some(context) = self.context.take() else {
+            return Ok(());
+        };
+        trace!("Parking the current session.");
+        let parked = mem::replace(&mut self.session, Session::new());
+        self.sessions.push((context, parked));
+        let _ = self.cancel_preview();
+        self.riti.finish_input_session();
+        self.candidate_list()?.hide();
+        Ok(())
+    }
+
+    fn close_current_session(&mut self) -> Result<()> {
+        trace!("Closing the current session.");
+        self.context = None;
+        self.session = Session::new();
+        self.riti.finish_input_session();
+        self.candidate_list()?.hide();
+        Ok(())
+    }
+}
@@ -4,17 +4,16 @@ use windows::{
         Foundation::E_FAIL,
         System::Com::{CLSCTX_INPROC_SERVER, CoCreateInstance},
         UI::TextServices::{
-            CLSID_TF_CategoryMgr, ITfCategoryMgr, ITfKeyEventSink, ITfKeystrokeMgr, ITfSource,
-            ITfTextInputProcessor_Impl, ITfTextInputProcessorEx_Impl, ITfThreadMgr,
-            ITfThreadMgrEventSink,
+            CLSID_TF_CategoryMgr, ITfCategoryMgr, ITfFunctionProvider, ITfKeyEventSink,
+            ITfKeystrokeMgr, ITfSource, ITfSourceSingle, ITfTextInputProcessor_Impl,
+            ITfTextInputProcessorEx_Impl, ITfThreadMgr, ITfThreadMgrEventSink, TF_TMAE_SECUREMODE,
         },
     },
     core::{Interface, Result, VARIANT},
 };
 
-use super::TextService;
-use super::key_event_sink::{register_preserved_keys, unregister_preserved_keys};
-use crate::DISPLAY_ATTR_ID;
+use super::{Mode, TextService, display_attribute_provider::DisplayAttribute, session::Session};
+use crate::{conf::Settings, extend::ResultExt, global};
 
 #[allow(non_snake_case)]
 impl ITfTextInputProcessor_Impl for TextService {
@@ -26,6 +25,16 @@ impl ITfTextInputProcessor_Impl for TextService {
         let thread_mgr = thread_mgr.ok_or(E_FAIL)?;
         inner.tid = tid;
         inner.thread_mgr = Some(thread_mgr.clone());
+        let settings = Settings::load_or_create().ok();
+        if let Some(settings) = &settings {
+            inner.disabled = settings.get_disabled();
+            inner.keymap = settings.get_keymap();
+            inner.mode = if settings.get_start_english() {
+                Mode::English
+            } else {
+                Mode::Bangla
+            };
+        }
         unsafe {
             // Use self as event sink to subscribe to events
             let keystroke_mgr = thread_mgr.cast::<ITfKeystrokeMgr>()?;
@@ -35,22 +44,38 @@ impl ITfTextInputProcessor_Impl for TextService {
                 true,
             )?;
             debug!("Added key event sink.");
-            register_preserved_keys(&keystroke_mgr, tid);
-            debug!("Registered preserved keys.");
+            if inner.disabled {
+                debug!("Disabled for {:?}.", global::exe_name());
+            } else if let Some(settings) = &settings {
+                inner.register_preserved_keys(settings)?;
+                debug!("Registered preserved keys.");
+            }
             inner.cookie = Some(thread_mgr.cast::<ITfSource>()?.AdviseSink(
                 &ITfThreadMgrEventSink::IID,
                 &inner.interface::<ITfThreadMgrEventSink>()?,
             )?);
             debug!("Added thread manager event sink.");
+            // reconversion is up to applications asking for it, typing goes on without
+            let function_provider = inner.interface::<ITfFunctionProvider>()?;
+            match thread_mgr.cast::<ITfSourceSingle>().and_then(|source| {
+                source.AdviseSingleSink(tid, &ITfFunctionProvider::IID, &function_provider)
+            }) {
+                Ok(()) => debug!("Added function provider."),
+                Err(e) => warn!("Failed to add function provider: {e}"),
+            }
             let _ = inner.create_candidate_list();
             // thread_mgr.cast::<ITfLangBarItemMgr>()?.AddItem(
             //     &inner.interface::<ITfLangBarItem>()?)?;
             // debug!("Added langbar item.");
-            if inner.display_attribute.is_none() {
+            if inner.display_attributes.is_empty() {
                 let category_mgr: ITfCategoryMgr =
                     CoCreateInstance(&CLSID_TF_CategoryMgr, None, CLSCTX_INPROC_SERVER)?;
-                let guid_atom = category_mgr.RegisterGUID(&DISPLAY_ATTR_ID)?;
-                inner.display_attribute = Some(VARIANT::from(guid_atom as i32));
+                for attribute in DisplayAttribute::ALL {
+                    let guid_atom = category_mgr.RegisterGUID(&attribute.guid())?;
+                    inner
+                        .display_attributes
+                        .push((attribute, VARIANT::from(guid_atom as i32)));
+                }
             }
             Ok(())
         }
@@ -61,10 +86,10 @@ impl ITfTextInputProcessor_Impl for TextService {
 
         trace!("Deactivate");
         let mut inner = self.write()?;
+        let _ = inner.unregister_preserved_keys().log_err();
         let thread_mgr = inner.thread_mgr()?;
         unsafe {
             let keystroke_mgr = thread_mgr.cast::<ITfKeystrokeMgr>()?;
-            unregister_preserved_keys(&keystroke_mgr);
             keystroke_mgr.UnadviseKeyEventSink(inner.tid)?;
             debug!("Removed key event sink.");
             if let Some(cookie) = inner.cookie {
@@ -74,6 +99,13 @@ impl ITfTextInputProcessor_Impl for TextService {
             } else {
                 warn!("Cookie for thread manager event sink is None.");
             }
+            match thread_mgr
+                .cast::<ITfSourceSingle>()
+                .and_then(|source| source.UnadviseSingleSink(inner.tid, &ITfFunctionProvider::IID))
+            {
+                Ok(()) => debug!("Removed function provider."),
+                Err(e) => warn!("Failed to remove function provider: {e}"),
+            }
             if let Some(candidate_list) = inner.candidate_list.as_ref() {
                 candidate_list.destroy()?;
             }
@@ -82,16 +114,26 @@ impl ITfTextInputProcessor_Impl for TextService {
         }
         inner.thread_mgr = None;
         inner.candidate_list = None;
+        inner.secure_mode = false;
+        inner.scoped_context = None;
+        inner.preview = None;
+        // the next activation starts over, with no document to go back to
+        inner.context = None;
+        inner.session = Session::new();
+        inner.sessions.clear();
+        inner.riti.finish_input_session();
         Ok(())
     }
 }
 
 #[allow(non_snake_case)]
 impl ITfTextInputProcessorEx_Impl for TextService {
-    fn ActivateEx(&self, thread_mgr: Option<&ITfThreadMgr>, tid: u32, _dwflags: u32) -> Result<()> {
+    fn ActivateEx(&self, thread_mgr: Option<&ITfThreadMgr>, tid: u32, dwflags: u32) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        self.Activate(thread_mgr, tid)
+        self.Activate(thread_mgr, tid)?;
+        self.write()?.secure_mode = dwflags & TF_TMAE_SECUREMODE != 0;
+        Ok(())
     }
 }
 
@@ -4,18 +4,23 @@ use windows::{
 };
 
 use super::TextService;
+use crate::conf::Settings;
 
 #[allow(non_snake_case, unused)]
 impl ITfThreadMgrEventSink_Impl for TextService {
     fn OnInitDocumentMgr(&self, pdim: Option<&ITfDocumentMgr>) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
+        // a new document manager has no context yet, sessions are opened as they are pushed
         Ok(())
     }
     fn OnUninitDocumentMgr(&self, pdim: Option<&ITfDocumentMgr>) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        Ok(())
+        match pdim {
+            Some(document_mgr) => self.write()?.close_sessions_of(document_mgr),
+            None => Ok(()),
+        }
     }
     fn OnSetFocus(
         &self,
@@ -24,16 +29,31 @@ impl ITfThreadMgrEventSink_Impl for TextService {
     ) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        self.write()?.abort()
+        let mut inner = self.write()?;
+        // the input scope is looked up again on the next key
+        inner.scoped_context = None;
+        inner.scope_mode = None;
+        // the layout may have been changed in the settings meanwhile
+        if let Ok(settings) = Settings::load_or_create() {
+            inner.refresh_preserved_keys(&settings);
+        }
+        // the composition stays with its document, the focus action is for when the focus
+        // leaves the application
+        inner.park_session()?;
+        inner.gain_focus(focus)
     }
-    fn OnPushContext(&self, pic: Option<&ITfContext>) -> Result<()> {
+    fn OnPushContext(&self, _pic: Option<&ITfContext>) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
+        // the session is opened once the context is typed into
         Ok(())
     }
     fn OnPopContext(&self, pic: Option<&ITfContext>) -> Result<()> {
         //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
 
-        Ok(())
+        match pic {
+            Some(context) => self.write()?.close_session(context),
+            None => Ok(()),
+        }
     }
 }
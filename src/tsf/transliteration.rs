@@ -0,0 +1,130 @@
+use std::ffi::OsString;
+
+use log::{debug, trace};
+use windows::{
+    Win32::UI::TextServices::{ITfContext, ITfRange},
+    core::Result,
+};
+
+use super::{TextServiceInner, edit_queue::Target, edit_session};
+use crate::extend::OsStrExt2;
+
+//----------------------------------------------------------------------------
+//
+//  Transliteration of selected text. Roman (Banglish) text that was pasted or
+//  typed elsewhere is converted word by word through riti and written back
+//  over the selection in a single edit, so that a single undo reverts it.
+//
+//----------------------------------------------------------------------------
+
+impl TextServiceInner {
+    /// Transliterate the roman text in the selection. It's replaced right away or, if the
+    /// preview is turned on, shown in the candidate list to be confirmed first.
+    /// Returns false if nothing is selected.
+    pub fn transliterate_selection(&mut self, context: &ITfContext) -> Result<bool> {
+        //log::info!("[{}:{};{}] {}()", file!(), line!(), column!(), crate::function!());
+
+        self.switch_context(context)?;
+        if self.session.composition.is_some() {
+            return Ok(false);
+        }
+        let Some((range, roman)) = edit_session::get_selected_text(&self.edits, self.tid, context)?
+        else {
+            return Ok(false);
+        };
+        self.update_engine();
+        let bangla = self.transliterate(&roman);
+        if bangla == roman {
+            debug!("Nothing in the selection to transliterate.");
+            return Ok(true);
+        }
+        if self.transliteration_preview {
+            self.show_preview(range, bangla)?;
+        } else {
+            self.replace(range, &bangla)?;
+        }
+        Ok(true)
+    }
+
+    /// Convert every roman word of `text` to its first suggestion from riti, or the one the
+    /// user picked last time. Whitespace and words that aren't roman are kept as they are.
+    fn transliterate(&mut self, text: &str) -> String {
+        let mut converted = String::with_capacity(text.len() * 3);
+        let mut word = Vec::new();
+        for ch in text.chars().chain([' ']) {
+            if !ch.is_whitespace() {
+                word.push(ch);
+                continue;
+            }
+            if !word.is_empty() {
+                match self.transliterate_word(&word) {
+                    Some(bangla) => converted.push_str(&bangla),
+                    None => converted.extend(&word),
+                }
+                word.clear();
+            }
+            converted.push(ch);
+        }
+        // drop the space chained to flush the last word
+        converted.pop();
+        converted
+    }
+
+    fn transliterate_word(&mut self, word: &[char]) -> Option<String> {
+        if !word.iter().all(char::is_ascii) {
+            return None;
+        }
+        let suggestion = self.type_roman(word);
+        self.riti.finish_input_session();
+        let suggestion = suggestion?;
+        let index = if suggestion.is_lonely() {
+            0
+        } else {
+            suggestion.previously_selected_index()
+        };
+        Some(suggestion.get_pre_edit_text(index))
+    }
+
+    fn show_preview(&mut self, range: ITfRange, bangla: String) -> Result<()> {
+        self.assure_candidate_list()?;
+        let candidate_list = self.candidate_list()?;
+        candidate_list.show(std::slice::from_ref(&bangla))?;
+        if let Ok((x, y)) = edit_session::get_pos(&self.edits, self.tid, self.context()?, &range) {
+            candidate_list.locate(x, y)?;
+        }
+        self.preview = Some((range, bangla));
+        Ok(())
+    }
+
+    /// Replace the selection with the transliteration in preview.
+    pub fn confirm_preview(&mut self) -> Result<()> {
+        trace!("confirm_preview");
+        let Some((range, bangla)) = self.preview.take() else {
+            return Ok(());
+        };
+        self.candidate_list()?.hide();
+        self.replace(range, &bangla)
+    }
+
+    fn replace(&self, range: ITfRange, text: &str) -> Result<()> {
+        let text = OsString::from(text).to_wchars();
+        let len = text.len();
+        self.edits.set_text(
+            self.tid,
+            self.context()?,
+            Target::Range(range),
+            text,
+            len,
+            Vec::new(),
+        )
+    }
+
+    /// Drop the transliteration in preview, leaving the selection as it was.
+    pub fn cancel_preview(&mut self) -> Result<()> {
+        if self.preview.take().is_some() {
+            trace!("cancel_preview");
+            self.candidate_list()?.hide();
+        }
+        Ok(())
+    }
+}
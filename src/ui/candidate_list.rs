@@ -339,6 +339,11 @@ impl CandidateList {
         self.state.write().unwrap().vertical = vertical;
     }
 
+    /// Whether candidates are stacked top-to-bottom.
+    pub fn is_vertical(&self) -> bool {
+        self.state.read().unwrap().vertical
+    }
+
     /// Trigger a repaint of the window with updated highlight.
     fn invalidate(&self) {
         let _ = self.repaint(false);